@@ -0,0 +1,91 @@
+//! Compares read/write throughput of a single `Mutex<HashMap>` against `ShardedMap`
+//! as the number of concurrent threads grows.
+//!
+//! Run with `cargo bench --bench sharded_map`.
+
+use micro_kv::storage::ShardedMap;
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::Instant;
+
+const KEYS: usize = 100_000;
+const OPS_PER_THREAD: usize = 500_000;
+/// One in every `WRITE_EVERY` operations is a write, the rest are reads.
+const WRITE_EVERY: usize = 10;
+
+trait Map: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Option<String>;
+    fn insert(&self, key: String, value: String);
+}
+
+impl Map for Mutex<HashMap<String, String>> {
+    fn get(&self, key: &str) -> Option<String> {
+        self.lock().unwrap().get(key).cloned()
+    }
+
+    fn insert(&self, key: String, value: String) {
+        self.lock().unwrap().insert(key, value);
+    }
+}
+
+impl Map for ShardedMap<String> {
+    fn get(&self, key: &str) -> Option<String> {
+        self.read(key).get(key).cloned()
+    }
+
+    fn insert(&self, key: String, value: String) {
+        self.write(&key).insert(key, value);
+    }
+}
+
+/// Runs the mixed workload on `threads` threads and returns millions of operations per second.
+fn run<M: Map>(map: Arc<M>, threads: usize) -> f64 {
+    let keys: Arc<Vec<String>> = Arc::new((0..KEYS).map(|i| format!("key:{}", i)).collect());
+    for key in keys.iter() {
+        map.insert(key.clone(), "{\"name\":\"John Doe\"}".to_string());
+    }
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let map = Arc::clone(&map);
+            let keys = Arc::clone(&keys);
+            thread::spawn(move || {
+                // Cheap xorshift so each thread touches keys in a different order.
+                let mut state = (t as u64 + 1) * 0x9e3779b97f4a7c15;
+                for op in 0..OPS_PER_THREAD {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let key = &keys[(state as usize) % KEYS];
+                    if op % WRITE_EVERY == 0 {
+                        map.insert(key.clone(), "{\"name\":\"Jane Doe\"}".to_string());
+                    } else {
+                        black_box(map.get(key));
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let ops = (threads * OPS_PER_THREAD) as f64;
+    ops / start.elapsed().as_secs_f64() / 1_000_000.0
+}
+
+fn main() {
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get()).max(4);
+    println!("{:>8} {:>16} {:>16}", "threads", "mutex (Mops/s)", "sharded (Mops/s)");
+
+    let mut threads = 1;
+    while threads <= max_threads {
+        let mutex = run(Arc::new(Mutex::new(HashMap::new())), threads);
+        let sharded = run(Arc::new(ShardedMap::new()), threads);
+        println!("{:>8} {:>16.2} {:>16.2}", threads, mutex, sharded);
+        threads *= 2;
+    }
+}
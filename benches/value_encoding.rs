@@ -0,0 +1,87 @@
+//! Compares storing values as serialized JSON strings, re-parsed on every read,
+//! against storing them pre-parsed as shared `serde_json::Value`s.
+//!
+//! Reports the heap memory held by each representation and the throughput of
+//! serving a read, i.e. turning a stored value into a response body.
+//!
+//! Run with `cargo bench --bench value_encoding`.
+
+use serde_json::{ json, Value };
+use std::alloc::{ GlobalAlloc, Layout, System };
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+use std::time::Instant;
+
+const KEYS: usize = 100_000;
+const READS: usize = 1_000_000;
+
+/// Global allocator that tracks the number of live heap bytes.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn document(i: usize) -> Value {
+    json!({
+        "_id": i.to_string(),
+        "name": "John Doe",
+        "email": format!("john{}@example.com", i),
+        "active": i.is_multiple_of(2),
+        "roles": ["reader", "writer"],
+        "address": { "city": "Stockholm", "zip": 11122 },
+    })
+}
+
+/// Builds a map with `build` and returns it along with the heap bytes it holds.
+fn measure<V>(build: impl Fn(Value) -> V) -> (HashMap<String, V>, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let map: HashMap<String, V> = (0..KEYS).map(|i| (format!("key:{}", i), build(document(i)))).collect();
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    (map, bytes)
+}
+
+/// Serves `READS` reads with `read` and returns millions of reads per second.
+fn throughput<V>(map: &HashMap<String, V>, read: impl Fn(&V) -> Vec<u8>) -> f64 {
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("key:{}", i)).collect();
+    let start = Instant::now();
+    for i in 0..READS {
+        black_box(read(&map[&keys[(i * 7919) % KEYS]]));
+    }
+    (READS as f64) / start.elapsed().as_secs_f64() / 1_000_000.0
+}
+
+fn main() {
+    let (strings, string_bytes) = measure(|value| serde_json::to_string(&value).unwrap());
+    let string_reads = throughput(&strings, |serialized| {
+        let value: Value = serde_json::from_str(serialized).unwrap();
+        serde_json::to_vec(&value).unwrap()
+    });
+    drop(strings);
+
+    let (values, value_bytes) = measure(Arc::new);
+    let value_reads = throughput(&values, |value| serde_json::to_vec(&**value).unwrap());
+
+    println!("{:>12} {:>12} {:>16}", "encoding", "heap (MiB)", "reads (M/s)");
+    for (name, bytes, reads) in [
+        ("String", string_bytes, string_reads),
+        ("Arc<Value>", value_bytes, value_reads),
+    ] {
+        println!("{:>12} {:>12.1} {:>16.2}", name, (bytes as f64) / (1024.0 * 1024.0), reads);
+    }
+}
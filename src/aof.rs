@@ -0,0 +1,735 @@
+use crate::engine::{ Data, Entry, Restored };
+use crate::eviction::Access;
+use crate::index::Indexes;
+use crate::{ from_unix_millis, to_unix_millis };
+use async_std::task;
+use micro_kv::storage::ShardedMap;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use slog::Logger;
+use std::collections::HashMap;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufRead, BufReader, BufWriter, Write };
+use std::mem;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+/// Controls when writes to the append-only log are synced to disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Sync after every record.
+    Always,
+    /// Sync once per second from a background task.
+    #[default]
+    EverySec,
+    /// Leave syncing to the operating system.
+    Never,
+}
+
+/// A single mutation recorded in the append-only log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Record {
+    Set {
+        key: String,
+        value: Arc<Value>,
+        /// Wall-clock deadline in milliseconds since the Unix epoch.
+        expires_at: Option<u64>,
+        /// Entry version; zero in logs written before versions existed.
+        #[serde(default)]
+        version: u64,
+        /// Sliding TTL in milliseconds.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sliding_ms: Option<u64>,
+    },
+    #[serde(rename = "set_bytes")]
+    SetBytes {
+        key: String,
+        content_type: String,
+        /// Hex-encoded value.
+        bytes: String,
+        expires_at: Option<u64>,
+        #[serde(default)]
+        version: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sliding_ms: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    Expire {
+        key: String,
+    },
+    /// Removal of every entry.
+    Flush,
+    /// Renewal of the expiry of an entry with a sliding TTL.
+    Touch {
+        key: String,
+        expires_at: u64,
+    },
+    /// The highest entry version issued so far, written first by a rewrite so the versions
+    /// of entries deleted before it are never issued again.
+    Version {
+        version: u64,
+    },
+    /// Records applied together, written as one line so a crash keeps all or none of them.
+    Batch {
+        records: Vec<Record>,
+    },
+    /// Creation of an index on `path` at runtime.
+    #[serde(rename = "create_index")]
+    CreateIndex {
+        path: String,
+    },
+    /// Removal of the index on `path`.
+    #[serde(rename = "drop_index")]
+    DropIndex {
+        path: String,
+    },
+}
+
+impl Record {
+    /// Returns the record that stores `entry` under `key`.
+    pub fn set(key: &str, entry: &Entry) -> Self {
+        let expires_at = entry.expiry.map(to_unix_millis);
+        let version = entry.version;
+        let sliding_ms = entry.sliding.map(|sliding| sliding.as_millis() as u64);
+        match &entry.value {
+            Data::Json(value) => Record::Set {
+                key: key.to_string(),
+                value: Arc::clone(value),
+                expires_at,
+                version,
+                sliding_ms,
+            },
+            Data::Bytes { content_type, bytes } => Record::SetBytes {
+                key: key.to_string(),
+                content_type: content_type.clone(),
+                bytes: encode_hex(bytes),
+                expires_at,
+                version,
+                sliding_ms,
+            },
+        }
+    }
+}
+
+/// Append-only log of every mutation applied to the database.
+pub struct AppendLog {
+    path: PathBuf,
+    state: Option<Mutex<LogState>>,
+    policy: FsyncPolicy,
+}
+
+struct LogState {
+    writer: BufWriter<File>,
+    /// Current size of the log file in bytes.
+    size: u64,
+    /// Size of the log file after it was opened or last rewritten.
+    base_size: u64,
+    /// Records appended while a rewrite is in progress, copied into the new file before the swap.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl AppendLog {
+    /// Opens the log at `path` for appending, creating it if necessary.
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(AppendLog {
+            path: path.to_path_buf(),
+            state: Some(
+                Mutex::new(LogState {
+                    writer: BufWriter::new(file),
+                    size,
+                    base_size: size,
+                    rewrite_buffer: None,
+                })
+            ),
+            policy,
+        })
+    }
+
+    /// Returns a log that discards every record.
+    pub fn disabled() -> Self {
+        AppendLog {
+            path: PathBuf::new(),
+            state: None,
+            policy: FsyncPolicy::Never,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.is_some()
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.policy
+    }
+
+    /// Appends a record, syncing it to disk if the policy is `Always`.
+    pub fn append(&self, record: &Record) -> io::Result<()> {
+        let Some(state) = &self.state else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut state = state.lock().unwrap();
+        state.writer.write_all(&line)?;
+        state.writer.flush()?;
+        if self.policy == FsyncPolicy::Always {
+            state.writer.get_ref().sync_data()?;
+        }
+        state.size += line.len() as u64;
+        if let Some(buffer) = &mut state.rewrite_buffer {
+            buffer.extend_from_slice(&line);
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered records and syncs the file to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        if let Some(state) = &self.state {
+            let mut state = state.lock().unwrap();
+            state.writer.flush()?;
+            state.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Returns true once the log has grown by `percentage` percent since it was
+    /// opened or last rewritten, and is at least `min_size` bytes.
+    pub fn needs_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        let Some(state) = &self.state else {
+            return false;
+        };
+        let state = state.lock().unwrap();
+        percentage > 0 &&
+            state.rewrite_buffer.is_none() &&
+            state.size >= min_size &&
+            state.size.saturating_mul(100) >= state.base_size.saturating_mul(100 + percentage)
+    }
+
+    /// Compacts the log by rewriting it from the live contents of `entries`, preceded by
+    /// the highest entry version issued so far, read from `version`, and the indexes
+    /// created at runtime.
+    ///
+    /// The new log is written and synced to a temporary file without holding any database
+    /// locks, nor the log's own, so appends carry on meanwhile. Records appended in the
+    /// meantime are buffered and copied over before the temporary file is atomically
+    /// renamed over the old one, so a crash at any point leaves either the old or the new
+    /// log intact.
+    pub fn rewrite(
+        &self,
+        entries: &ShardedMap<Entry>,
+        version: &AtomicU64,
+        indexes: &Indexes
+    ) -> io::Result<()> {
+        let Some(state) = &self.state else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "append-only log is disabled"));
+        };
+
+        {
+            let mut state = state.lock().unwrap();
+            if state.rewrite_buffer.is_some() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "rewrite already in progress"));
+            }
+            state.rewrite_buffer = Some(Vec::new());
+        }
+        // Shards are copied one at a time after buffering has started, so a record may
+        // end up both in the copy and in the buffer. Records are idempotent, so
+        // replaying it twice yields the same contents.
+        // Versions issued from now on are in the buffered records.
+        let mut records = vec![Record::Version { version: version.load(Ordering::Relaxed) }];
+        records.extend(
+            indexes
+                .persisted()
+                .into_iter()
+                .map(|path| Record::CreateIndex { path })
+        );
+        for shard in entries.shards() {
+            records.extend(
+                shard
+                    .read()
+                    .iter()
+                    .map(|(key, entry)| Record::set(key, entry))
+            );
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".rewrite");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let result = self.write_rewrite(&tmp_path, &records, state);
+        if result.is_err() {
+            state.lock().unwrap().rewrite_buffer = None;
+            if tmp_path.exists() {
+                let _ = fs::remove_file(&tmp_path);
+            }
+        }
+        result
+    }
+
+    fn write_rewrite(
+        &self,
+        tmp_path: &Path,
+        records: &[Record],
+        state: &Mutex<LogState>
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(tmp_path)?);
+        for record in records {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        // Records buffered while the copy was written are synced along with it, so only
+        // those appended during that sync are left for the tail written under the lock.
+        let buffered = state.lock().unwrap().rewrite_buffer.as_mut().map(mem::take);
+        writer.write_all(&buffered.unwrap_or_default())?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        let mut state = state.lock().unwrap();
+        if let Some(buffer) = state.rewrite_buffer.take() {
+            writer.write_all(&buffer)?;
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        drop(writer);
+
+        // Open the append handle before the rename so nothing can fail once the new log is in place.
+        let file = OpenOptions::new().append(true).open(tmp_path)?;
+        let size = file.metadata()?.len();
+        fs::rename(tmp_path, &self.path)?;
+        state.writer = BufWriter::new(file);
+        state.size = size;
+        state.base_size = size;
+        drop(state);
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the database contents by replaying the log at `path`.
+///
+/// Returns the entries along with the highest version issued while writing the log,
+/// including to entries since deleted, and the indexes created at runtime and not since
+/// dropped. A missing file yields an empty map. A torn record at the end of the file, left
+/// behind by a crash mid-write, is truncated so new records can be appended. Any other
+/// invalid record fails the replay and leaves the file untouched.
+pub fn replay(path: &Path) -> io::Result<Restored> {
+    let mut restored = Restored::default();
+    let file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(restored);
+        }
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    let mut reader = BufReader::new(&file);
+    let mut line = Vec::new();
+    let mut valid_len = 0;
+    for number in 1.. {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        if !line.ends_with(b"\n") {
+            // Only the last record can lack its newline, and only if writing it was cut short.
+            file.set_len(valid_len)?;
+            break;
+        }
+        let record = serde_json::from_slice::<Record>(&line).map_err(|e| {
+            let message = format!("invalid record on line {} of append-only log: {}", number, e);
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })?;
+        valid_len += read as u64;
+
+        apply(&mut restored, record)?;
+    }
+    Ok(restored)
+}
+
+/// Applies a replayed record to the `restored` contents, raising their version to the
+/// versions it carries.
+fn apply(restored: &mut Restored, record: Record) -> io::Result<()> {
+    let (entries, version, indexes) = restored;
+    match record {
+        Record::Set { key, value, expires_at, version: entry_version, sliding_ms } => {
+            *version = (*version).max(entry_version);
+            let value = Data::Json(value);
+            let entry = Entry {
+                value,
+                expiry: None,
+                logged_expiry: None,
+                sliding: sliding_ms.map(Duration::from_millis),
+                version: entry_version,
+                access: Access::new(),
+            };
+            restore(entries, key, entry, expires_at);
+        }
+        Record::SetBytes {
+            key,
+            content_type,
+            bytes,
+            expires_at,
+            version: entry_version,
+            sliding_ms,
+        } => {
+            *version = (*version).max(entry_version);
+            let bytes = decode_hex(&bytes).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid hex in append-only log")
+            })?;
+            let value = Data::Bytes { content_type, bytes: bytes.into() };
+            let entry = Entry {
+                value,
+                expiry: None,
+                logged_expiry: None,
+                sliding: sliding_ms.map(Duration::from_millis),
+                version: entry_version,
+                access: Access::new(),
+            };
+            restore(entries, key, entry, expires_at);
+        }
+        Record::Touch { key, expires_at } => {
+            if let Some(entry) = entries.remove(&key) {
+                restore(entries, key, entry, Some(expires_at));
+            }
+        }
+        Record::Delete { key } | Record::Expire { key } => {
+            entries.remove(&key);
+        }
+        Record::Flush => entries.clear(),
+        Record::Version { version: issued } => {
+            *version = (*version).max(issued);
+        }
+        Record::Batch { records } => {
+            for record in records {
+                apply(restored, record)?;
+            }
+        }
+        Record::CreateIndex { path } => {
+            indexes.insert(path);
+        }
+        Record::DropIndex { path } => {
+            indexes.remove(&path);
+        }
+    }
+    Ok(())
+}
+
+/// Applies a replayed write of `entry` expiring at `expires_at`, dropping keys whose
+/// deadline passed while the server was down.
+fn restore(entries: &mut HashMap<String, Entry>, key: String, mut entry: Entry, expires_at: Option<u64>) {
+    match expires_at {
+        None => {
+            entries.insert(key, entry);
+        }
+        Some(millis) =>
+            match from_unix_millis(millis) {
+                Some(expiry) => {
+                    entry.expiry = Some(expiry);
+                    entry.logged_expiry = Some(expiry);
+                    entries.insert(key, entry);
+                }
+                None => {
+                    entries.remove(&key);
+                }
+            }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Syncs the log to disk once per second, reporting failures to `logger`.
+pub async fn sync_every_second(log: Arc<AppendLog>, logger: Logger) {
+    loop {
+        task::sleep(Duration::from_secs(1)).await;
+        if let Err(e) = log.sync() {
+            slog::error!(logger, "Error syncing append-only log: {:?}", e);
+        }
+    }
+}
+
+/// Rewrites the log whenever it has grown past the configured ratio, reporting failures to
+/// `logger`.
+pub async fn rewrite_when_grown(
+    entries: Arc<ShardedMap<Entry>>,
+    version: Arc<AtomicU64>,
+    indexes: Arc<Indexes>,
+    log: Arc<AppendLog>,
+    percentage: u64,
+    min_size: u64,
+    logger: Logger
+) {
+    loop {
+        task::sleep(Duration::from_secs(1)).await;
+        if !log.needs_rewrite(percentage, min_size) {
+            continue;
+        }
+        let entries = Arc::clone(&entries);
+        let version = Arc::clone(&version);
+        let indexes = Arc::clone(&indexes);
+        let log = Arc::clone(&log);
+        let rewrite = move || log.rewrite(&entries, &version, &indexes);
+        if let Err(e) = task::spawn_blocking(rewrite).await {
+            slog::error!(logger, "Error rewriting append-only log: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query;
+    use std::collections::BTreeSet;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    /// Returns the path of a log in a new, empty temporary directory.
+    fn log_path() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("micro_kv-aof-{}-{}", std::process::id(), count);
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("appendonly.aof")
+    }
+
+    fn set(key: &str, value: Value) -> Record {
+        Record::Set {
+            key: key.to_string(),
+            value: Arc::new(value),
+            expires_at: None,
+            version: 1,
+            sliding_ms: None,
+        }
+    }
+
+    fn append_raw(path: &Path, bytes: &[u8]) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+    }
+
+    fn write(path: &Path, records: &[Record]) {
+        let log = AppendLog::open(path, FsyncPolicy::Always).unwrap();
+        for record in records {
+            log.append(record).unwrap();
+        }
+    }
+
+    fn is_rewriting(log: &AppendLog) -> bool {
+        log.state.as_ref().unwrap().lock().unwrap().rewrite_buffer.is_some()
+    }
+
+    fn json(entries: &HashMap<String, Entry>, key: &str) -> Option<Value> {
+        match &entries.get(key)?.value {
+            Data::Json(value) => Some(Value::clone(value)),
+            Data::Bytes { .. } => None,
+        }
+    }
+
+    #[test]
+    fn missing_log_replays_empty() {
+        assert!(replay(&log_path()).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn replays_writes_and_deletes() {
+        let path = log_path();
+        write(&path, &[
+            set("a", Value::from(1)),
+            set("b", Value::from("two")),
+            set("a", Value::from(3)),
+            Record::Delete { key: "b".to_string() },
+        ]);
+        let (entries, _, _) = replay(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(json(&entries, "a"), Some(Value::from(3)));
+    }
+
+    #[test]
+    fn replays_highest_version_of_deleted_entries() {
+        let path = log_path();
+        let mut record = set("b", Value::from(2));
+        if let Record::Set { version, .. } = &mut record {
+            *version = 7;
+        }
+        write(&path, &[set("a", Value::from(1)), record, Record::Delete { key: "b".to_string() }]);
+        assert_eq!(replay(&path).unwrap().1, 7);
+    }
+
+    #[test]
+    fn rewrite_keeps_highest_version() {
+        let path = log_path();
+        write(&path, &[set("a", Value::from(1))]);
+        let (entries, _, _) = replay(&path).unwrap();
+        let log = AppendLog::open(&path, FsyncPolicy::Always).unwrap();
+        let indexes = Indexes::new();
+        log.rewrite(&entries.into_iter().collect(), &AtomicU64::new(9), &indexes).unwrap();
+
+        let (entries, version, _) = replay(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(version, 9);
+    }
+
+    #[test]
+    fn rewrite_keeps_records_appended_meanwhile() {
+        let path = log_path();
+        write(&path, &[set("a", Value::from(1)), set("b", Value::from(2))]);
+        let entries: ShardedMap<Entry> = replay(&path).unwrap().0.into_iter().collect();
+        let log = AppendLog::open(&path, FsyncPolicy::Never).unwrap();
+        let indexes = Indexes::new();
+        thread::scope(|scope| {
+            // Holding a shard keeps the rewrite from finishing its copy of the entries.
+            let shard = entries.shards()[0].write();
+            let rewrite = scope.spawn(|| log.rewrite(&entries, &AtomicU64::new(1), &indexes));
+            while !is_rewriting(&log) {
+                thread::yield_now();
+            }
+            log.append(&set("c", Value::from(3))).unwrap();
+            log.append(&(Record::Delete { key: "a".to_string() })).unwrap();
+            drop(shard);
+            rewrite.join().unwrap().unwrap();
+        });
+        log.append(&set("d", Value::from(4))).unwrap();
+
+        let (entries, _, _) = replay(&path).unwrap();
+        let mut keys: Vec<&String> = entries.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["b", "c", "d"]);
+    }
+
+    #[test]
+    fn failed_rewrite_leaves_the_log_intact() {
+        let path = log_path();
+        write(&path, &[set("a", Value::from(1))]);
+        let contents = fs::read(&path).unwrap();
+        let log = AppendLog::open(&path, FsyncPolicy::Always).unwrap();
+        // The temporary file cannot be created where a directory is in the way.
+        let tmp_path = path.with_extension("aof.rewrite");
+        fs::create_dir(&tmp_path).unwrap();
+
+        let empty = ShardedMap::new();
+        let indexes = Indexes::new();
+        assert!(log.rewrite(&empty, &AtomicU64::new(1), &indexes).is_err());
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        log.append(&set("b", Value::from(2))).unwrap();
+        assert_eq!(replay(&path).unwrap().0.len(), 2);
+
+        // The failed rewrite is no longer in progress, so the next one goes ahead.
+        fs::remove_dir(&tmp_path).unwrap();
+        log.rewrite(&empty, &AtomicU64::new(1), &indexes).unwrap();
+        assert!(replay(&path).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn replays_and_rewrites_indexes_created_at_runtime() {
+        let path = log_path();
+        let create = |path: &str| Record::CreateIndex { path: path.to_string() };
+        let drop = Record::DropIndex { path: "$.a".to_string() };
+        write(&path, &[create("$.a"), create("$.b"), drop, Record::Flush, create("$.b")]);
+        let (_, _, paths) = replay(&path).unwrap();
+        assert_eq!(paths, BTreeSet::from(["$.b".to_string()]));
+
+        // Indexes from the settings are created again on startup rather than logged.
+        let empty = ShardedMap::new();
+        let indexes = Indexes::new();
+        indexes.create("$.b", query::Path::parse("$.b").unwrap(), true, &empty);
+        indexes.create("$.c", query::Path::parse("$.c").unwrap(), false, &empty);
+        let log = AppendLog::open(&path, FsyncPolicy::Always).unwrap();
+        log.rewrite(&empty, &AtomicU64::new(1), &indexes).unwrap();
+        let (_, _, paths) = replay(&path).unwrap();
+        assert_eq!(paths, BTreeSet::from(["$.b".to_string()]));
+    }
+
+    #[test]
+    fn replays_bytes() {
+        let path = log_path();
+        let entry = Entry {
+            value: Data::Bytes {
+                content_type: "text/plain".to_string(),
+                bytes: vec![0xff, 0].into(),
+            },
+            expiry: None,
+            logged_expiry: None,
+            sliding: None,
+            version: 1,
+            access: Access::new(),
+        };
+        write(&path, &[Record::set("raw", &entry)]);
+        match &replay(&path).unwrap().0["raw"].value {
+            Data::Bytes { content_type, bytes } => {
+                assert_eq!(content_type, "text/plain");
+                assert_eq!(&bytes[..], &[0xff, 0]);
+            }
+            Data::Json(_) => panic!("bytes replayed as JSON"),
+        }
+    }
+
+    #[test]
+    fn truncates_torn_tail() {
+        let path = log_path();
+        write(&path, &[set("a", Value::from(1))]);
+        let valid_len = fs::metadata(&path).unwrap().len();
+        // Cut short in the middle of the two-byte encoding of "é".
+        let torn = b"{\"op\":\"set\",\"key\":\"b\",\"value\":\"caf\xc3";
+        append_raw(&path, torn);
+
+        let (entries, _, _) = replay(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+
+        write(&path, &[set("c", Value::from(2))]);
+        assert_eq!(replay(&path).unwrap().0.len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_record_before_the_end() {
+        let path = log_path();
+        write(&path, &[set("a", Value::from(1))]);
+        append_raw(&path, b"{\"op\":\"unknown\"}\n");
+        write(&path, &[set("b", Value::from(2))]);
+        let len = fs::metadata(&path).unwrap().len();
+
+        let error = replay(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8_before_the_end() {
+        let path = log_path();
+        write(&path, &[set("a", Value::from(1))]);
+        append_raw(&path, b"{\"op\":\"delete\",\"key\":\"\xff\"}\n");
+        write(&path, &[set("b", Value::from(2))]);
+
+        assert_eq!(replay(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
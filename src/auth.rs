@@ -0,0 +1,167 @@
+use crate::config::Config;
+use crate::error::{ self, Error };
+use rocket::request::{ FromRequest, Outcome, Request };
+use serde::Deserialize;
+use std::fmt;
+
+/// What a request does, and so which access control rules allow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Reading a key or its TTL.
+    Get,
+    /// Writing a key or its TTL.
+    Set,
+    /// Deleting a key.
+    Delete,
+    /// Listing keys, or reading the indexes and stats of a namespace.
+    List,
+    /// Managing indexes and namespaces, flushing and compacting.
+    Admin,
+}
+
+impl Action {
+    const ALL: [Action; 5] = [Action::Get, Action::Set, Action::Delete, Action::List, Action::Admin];
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Action::Get => "get",
+            Action::Set => "set",
+            Action::Delete => "delete",
+            Action::List => "list",
+            Action::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An access control rule, allowing the holder of an API key some actions on some keys.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    /// The API key the rule applies to.
+    pub key: String,
+    /// Namespaces the rule applies to, or all of them when empty.
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// Prefixes of the keys the rule applies to, or all keys when empty.
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Actions the rule allows.
+    pub operations: Vec<Action>,
+}
+
+impl Rule {
+    /// A rule allowing `operations` on every key of every namespace.
+    fn global(key: &str, operations: &[Action]) -> Self {
+        Rule {
+            key: key.to_string(),
+            namespaces: Vec::new(),
+            prefixes: Vec::new(),
+            operations: operations.to_vec(),
+        }
+    }
+
+    /// Returns true if the rule allows `action` on `key` in `namespace`. An empty `key`
+    /// stands for the whole namespace, and is only allowed by rules without prefixes.
+    fn allows(&self, action: Action, namespace: &str, key: &str) -> bool {
+        let matches = |prefix: &String| !key.is_empty() && key.starts_with(prefix.as_str());
+        self.covers(namespace) &&
+            self.operations.contains(&action) &&
+            (self.prefixes.is_empty() || self.prefixes.iter().any(matches))
+    }
+
+    fn covers(&self, namespace: &str) -> bool {
+        self.namespaces.is_empty() || self.namespaces.iter().any(|name| name == namespace)
+    }
+}
+
+/// Access control rules from the configuration, including those of the read-only and
+/// read-write API keys. Requests need no credentials when there are none.
+pub struct Credentials {
+    rules: Vec<Rule>,
+}
+
+impl Credentials {
+    pub fn new(config: &Config) -> Self {
+        let read_only = config.read_only_keys.iter().map(|key| Rule::global(key, &[Action::Get, Action::List]));
+        let read_write = config.read_write_keys.iter().map(|key| Rule::global(key, &Action::ALL));
+        let rules = read_only
+            .chain(read_write)
+            .chain(config.acl.iter().cloned())
+            .filter(|rule| !rule.key.is_empty())
+            .collect();
+        Credentials { rules }
+    }
+
+    /// Returns the rules for `key`, or `None` if it is not valid.
+    fn rules(&self, key: &str) -> Option<Vec<Rule>> {
+        // Every key is compared in full, so the time taken reveals nothing about them.
+        let rules: Vec<Rule> = self.rules
+            .iter()
+            .filter(|rule| constant_time_eq(rule.key.as_bytes(), key.as_bytes()))
+            .cloned()
+            .collect();
+        (!rules.is_empty()).then_some(rules)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The caller of a request, authenticated by an API key in the `X-API-Key` header or
+/// a bearer token in the `Authorization` header.
+pub struct Caller {
+    rules: Vec<Rule>,
+}
+
+impl Caller {
+    /// Returns true if the caller may perform `action` on `key` in `namespace`, or on the
+    /// whole namespace if `key` is empty.
+    pub fn allows(&self, action: Action, namespace: &str, key: &str) -> bool {
+        self.rules.iter().any(|rule| rule.allows(action, namespace, key))
+    }
+
+    /// Checks that the caller may perform `action` on `key` in `namespace`, or on the
+    /// whole namespace if `key` is empty.
+    pub fn require(&self, action: Action, namespace: &str, key: &str) -> Result<(), Error> {
+        if self.allows(action, namespace, key) {
+            return Ok(());
+        }
+        let message = match key {
+            "" => format!("Credentials do not allow {} in namespace: {}", action, namespace),
+            key => format!("Credentials do not allow {} on key: {}", action, key),
+        };
+        Err(Error::Forbidden(message))
+    }
+
+    /// Returns true if any rule of the caller applies to `namespace`.
+    pub fn sees(&self, namespace: &str) -> bool {
+        self.rules.iter().any(|rule| rule.covers(namespace))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let credentials = req.rocket().state::<Credentials>().expect("credentials are managed");
+        if credentials.rules.is_empty() {
+            return Outcome::Success(Caller { rules: vec![Rule::global("", &Action::ALL)] });
+        }
+        let bearer = req.headers().get_one("Authorization").and_then(|header| {
+            let (scheme, token) = header.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim())
+        });
+        let Some(key) = bearer.or_else(|| req.headers().get_one("X-API-Key")) else {
+            return error::fail(req, Error::Unauthorized("Missing API key".to_string()));
+        };
+        match credentials.rules(key) {
+            Some(rules) => Outcome::Success(Caller { rules }),
+            None => error::fail(req, Error::Unauthorized("Invalid API key".to_string())),
+        }
+    }
+}
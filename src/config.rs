@@ -0,0 +1,74 @@
+use crate::aof::FsyncPolicy;
+use crate::auth::Rule;
+use crate::engine::Engine;
+use crate::eviction::EvictionPolicy;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Application settings, read from `Rocket.toml` or `ROCKET_*` environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Storage engine holding the data.
+    pub storage_engine: Engine,
+    /// Path of the append-only log. Persistence is disabled when unset.
+    pub aof_path: Option<PathBuf>,
+    /// How often the append-only log is flushed to disk.
+    pub aof_fsync: FsyncPolicy,
+    /// Growth since the last rewrite, in percent, that triggers a compaction. Zero disables it.
+    pub aof_rewrite_percentage: u64,
+    /// Minimum size in bytes of the append-only log before it is compacted automatically.
+    pub aof_rewrite_min_size: u64,
+    /// Directory for periodic snapshots. Snapshots are disabled when unset.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Seconds between snapshots.
+    pub snapshot_interval: u64,
+    /// Number of snapshot files to keep.
+    pub snapshot_retain: usize,
+    /// Paths within JSON values to index in every namespace on startup, in addition to those
+    /// created at runtime.
+    pub indexes: Vec<String>,
+    /// Longest key, in bytes. Zero means no limit.
+    pub max_key_length: usize,
+    /// Largest value, in bytes of its compact JSON encoding or of its raw bytes. Zero means no limit.
+    pub max_value_size: usize,
+    /// Most entries all namespaces together may hold. Zero means no limit.
+    pub max_entries: usize,
+    /// Approximate memory, in bytes, all entries together may take up. Zero means no limit.
+    pub max_memory: usize,
+    /// Which keys to evict when a write would exceed `max_entries` or `max_memory`.
+    pub eviction_policy: EvictionPolicy,
+    /// API keys allowed to read but not write. Requests need no key when no keys or rules are set.
+    pub read_only_keys: Vec<String>,
+    /// API keys allowed to read and write.
+    pub read_write_keys: Vec<String>,
+    /// Access control rules, each allowing an API key some actions on some keys.
+    pub acl: Vec<Rule>,
+    /// Namespaces to create on startup, in addition to the default one and those found on disk.
+    pub namespaces: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            storage_engine: Engine::default(),
+            aof_path: None,
+            aof_fsync: FsyncPolicy::default(),
+            aof_rewrite_percentage: 100,
+            aof_rewrite_min_size: 64 * 1024 * 1024,
+            snapshot_dir: None,
+            snapshot_interval: 300,
+            snapshot_retain: 2,
+            indexes: Vec::new(),
+            max_key_length: 0,
+            max_value_size: 0,
+            max_entries: 0,
+            max_memory: 0,
+            eviction_policy: EvictionPolicy::default(),
+            read_only_keys: Vec::new(),
+            read_write_keys: Vec::new(),
+            acl: Vec::new(),
+            namespaces: Vec::new(),
+        }
+    }
+}
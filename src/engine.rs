@@ -0,0 +1,1224 @@
+use crate::aof::{ self, AppendLog, FsyncPolicy, Record };
+use crate::config::Config;
+use crate::eviction::{ self, Access, Evictable, EvictionPolicy, SAMPLES };
+use crate::limits::Capacity;
+use crate::expiry::ExpiryQueue;
+use crate::index::Indexes;
+use crate::precondition::Precondition;
+use crate::query::Path;
+use crate::snapshot;
+use crate::ttl;
+use crate::{ to_unix_millis, ExpiryTime };
+use async_std::task;
+use micro_kv::storage::ShardedMap;
+use serde::{ Deserialize, Serialize, Serializer };
+use serde_json::Value;
+use slog::Logger;
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::{ BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet };
+use std::hash::BuildHasher;
+use std::io;
+use std::ops::Bound;
+use std::str;
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering as AtomicOrdering };
+use std::sync::{ Arc, Mutex, Weak };
+use std::time::{ Duration, Instant };
+
+/// Maximum number of keys expired per batch by the expiry task.
+const EXPIRY_BATCH_SIZE: usize = 1024;
+
+/// A stored value: a JSON document, or opaque bytes along with their media type.
+///
+/// Values are kept parsed and shared, so reading an entry never re-parses or deep-copies it.
+#[derive(Debug, Clone)]
+pub enum Data {
+    Json(Arc<Value>),
+    Bytes {
+        content_type: String,
+        bytes: Arc<[u8]>,
+    },
+}
+
+impl Data {
+    /// Returns `"base64"` for byte values that are not valid UTF-8, and so are serialized
+    /// in base64, or `None` for values serialized as-is.
+    pub fn encoding(&self) -> Option<&'static str> {
+        match self {
+            Data::Bytes { bytes, .. } if str::from_utf8(bytes).is_err() => Some("base64"),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Data {
+    /// Serializes JSON values as-is and byte values as a string if they are valid UTF-8,
+    /// or in base64 otherwise.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Data::Json(value) => value.serialize(serializer),
+            Data::Bytes { bytes, .. } => match str::from_utf8(bytes) {
+                Ok(text) => text.serialize(serializer),
+                Err(_) => encode_base64(bytes).serialize(serializer),
+            },
+        }
+    }
+}
+
+/// Encodes `bytes` in padded base64, with the standard alphabet of RFC 4648.
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (u32::from(byte) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// A stored value, its expiry deadline and its version.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub value: Data,
+    pub expiry: ExpiryTime,
+    /// Expiry last written to the append-only log, which renewals of a sliding TTL only
+    /// update once it falls behind by half that TTL.
+    pub logged_expiry: ExpiryTime,
+    /// TTL the expiry is renewed to whenever the entry is read, for sliding expiration.
+    pub sliding: Option<Duration>,
+    /// Increases with every write, and is never reused for another write to any key.
+    pub version: u64,
+    pub access: Access,
+}
+
+/// Contents restored from the append-only log or a snapshot: the entries, the highest
+/// version issued and the paths of the indexes created at runtime.
+pub type Restored = (HashMap<String, Entry>, u64, BTreeSet<String>);
+
+impl Entry {
+    /// Returns true if the entry is no longer live at `now`.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expiry.is_some_and(|e| e <= now)
+    }
+}
+
+/// Reasons a write to a storage engine can be refused or fail.
+#[derive(Debug)]
+pub enum WriteError {
+    /// The write requires an existing entry, but the key does not exist.
+    KeyNotFound,
+    /// The write required the key to be absent, but it exists.
+    KeyExists,
+    /// The current version of the entry does not satisfy the write's precondition.
+    PreconditionFailed,
+    /// The stored value cannot be updated as requested, e.g. incrementing a non-number.
+    InvalidValue(String),
+    /// The key is longer than the limit, in bytes.
+    KeyTooLong(usize),
+    /// The value is larger than the limit, in bytes.
+    ValueTooLarge(usize),
+    /// The write does not fit within the entry or memory limit, and no key can be evicted
+    /// to make room.
+    OutOfMemory,
+    /// Persisting the write failed.
+    Io(io::Error),
+}
+
+impl From<io::Error> for WriteError {
+    fn from(e: io::Error) -> Self {
+        WriteError::Io(e)
+    }
+}
+
+/// Derives the new value, expiry and sliding TTL of an entry from its current state,
+/// for `Storage::update`.
+pub type Update<'a> = dyn FnMut(Option<&Entry>) -> Result<
+    (Data, ExpiryTime, Option<Duration>),
+    WriteError
+> +
+    'a;
+
+/// Operations the request handlers need from a storage engine.
+///
+/// Implementations are responsible for expiry and durability; entries that have
+/// expired are never returned.
+pub trait Storage: Send + Sync {
+    /// Returns the entry stored under `key`, renewing its expiry if it slides.
+    fn get(&self, key: &str) -> Option<Entry>;
+
+    /// Returns the entry stored under `key` without counting as a read, so its expiry
+    /// is left as it is.
+    fn peek(&self, key: &str) -> Option<Entry>;
+
+    /// Stores `value` under `key` if `precondition` holds, replacing any existing entry,
+    /// and returns the version of the new entry.
+    ///
+    /// With a `sliding` TTL, the expiry is renewed to that TTL whenever the entry is read.
+    fn set(
+        &self,
+        key: &str,
+        value: Data,
+        expiry: ExpiryTime,
+        sliding: Option<Duration>,
+        precondition: &Precondition
+    ) -> Result<u64, WriteError>;
+
+    /// Replaces the entry under `key`, if `precondition` holds, with the value, expiry and
+    /// sliding TTL that `update` derives from the current entry, or from `None` if there is none.
+    ///
+    /// No other write to `key` can happen in between, so the update is atomic.
+    /// Returns the new entry.
+    fn update(
+        &self,
+        key: &str,
+        precondition: &Precondition,
+        update: &mut Update
+    ) -> Result<Entry, WriteError>;
+
+    /// Removes `key` if `precondition` holds, returning whether it existed.
+    fn delete(&self, key: &str, precondition: &Precondition) -> Result<bool, WriteError>;
+
+    /// Returns the entries stored under `keys`, in order, all read at the same instant.
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<Entry>>;
+
+    /// Stores every `(key, value, expiry)` of `entries` at once, returning their versions:
+    /// readers observe either none or all of them, and so does a restart after a crash.
+    fn set_many(&self, entries: Vec<(String, Data, ExpiryTime)>) -> Result<Vec<u64>, WriteError>;
+
+    /// Removes `keys` at once, returning whether each existed beforehand. Keys are removed
+    /// in order, so a key given again is reported missing by then.
+    ///
+    /// If `atomic` is set and any of the keys does not exist, nothing is removed.
+    fn delete_many(&self, keys: &[&str], atomic: bool) -> Result<Vec<bool>, WriteError>;
+
+    /// Starts maintaining an index on the values at `path` within JSON values, named by the
+    /// text of the path, returning false if there already is one.
+    fn create_index(&self, name: &str, path: Path) -> io::Result<bool>;
+
+    /// Drops the index named `name`, returning whether it existed.
+    fn drop_index(&self, name: &str) -> io::Result<bool>;
+
+    /// Returns the names of all indexes, sorted.
+    fn indexes(&self) -> Vec<String>;
+
+    /// Returns the entries whose value at the path of the index named `name` equals `value`,
+    /// in key order, or `None` if there is no such index.
+    fn lookup(&self, name: &str, value: &Value) -> Option<Vec<(String, Entry)>>;
+
+    /// Returns the approximate number of bytes taken up by the entries.
+    fn memory(&self) -> usize;
+
+    /// Removes every entry, returning how many there were.
+    fn flush(&self) -> io::Result<usize>;
+
+    /// Calls `visit` with every entry.
+    fn scan(&self, visit: &mut dyn FnMut(&str, &Entry));
+
+    /// Returns up to `limit` entries whose keys sort after `after` and that satisfy `filter`,
+    /// in key order.
+    ///
+    /// Paging with the last returned key as `after` visits every key that exists for
+    /// the whole scan exactly once, regardless of writes made in between.
+    fn scan_page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+        filter: &dyn Fn(&str, &Entry) -> bool
+    ) -> Vec<(String, Entry)> {
+        // Max-heap holding the smallest `limit` keys seen so far.
+        let mut page: BinaryHeap<ByKey> = BinaryHeap::with_capacity(limit + 1);
+        self.scan(
+            &mut (|key, entry| {
+                if limit == 0 || after.is_some_and(|after| key <= after) || !filter(key, entry) {
+                    return;
+                }
+                let largest = page.peek().map(|largest| largest.0.as_str());
+                if page.len() == limit && largest.is_some_and(|largest| key >= largest) {
+                    return;
+                }
+                page.push(ByKey(key.to_string(), entry.clone()));
+                if page.len() > limit {
+                    page.pop();
+                }
+            })
+        );
+        page.into_sorted_vec()
+            .into_iter()
+            .map(|ByKey(key, entry)| (key, entry))
+            .collect()
+    }
+
+    /// Compacts the engine's on-disk representation, if it has one.
+    fn compact(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "storage engine cannot be compacted"))
+    }
+}
+
+/// An entry ordered by its key alone.
+struct ByKey(String, Entry);
+
+impl PartialEq for ByKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for ByKey {}
+
+impl PartialOrd for ByKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// Returns the access history of an entry being written over `current`: that of `current`
+/// with this write counted, so overwriting a key keeps how often it was used.
+fn accessed(current: Option<&Entry>) -> Access {
+    match current {
+        Some(entry) => {
+            entry.access.touch();
+            entry.access.clone()
+        }
+        None => Access::new(),
+    }
+}
+
+/// Returns an unpredictable number, for sampling keys to evict.
+fn random() -> usize {
+    // Every `RandomState` is seeded differently, which is all the randomness sampling needs.
+    RandomState::new().hash_one(()) as usize
+}
+
+/// Storage engines that can be selected with the `storage_engine` setting.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// Sharded in-memory map, optionally persisted by an append-only log and snapshots.
+    #[default]
+    Memory,
+}
+
+/// Opens the storage engine selected by `config` and starts its background tasks.
+///
+/// The engine counts its entries towards `capacity`, which may be shared with other engines,
+/// and reports errors in its background tasks to `log`.
+pub fn open(
+    config: &Config,
+    capacity: Arc<Capacity>,
+    log: &Logger
+) -> io::Result<Arc<dyn Storage>> {
+    match config.storage_engine {
+        Engine::Memory => Ok(MemoryStorage::open(config, capacity, log)?),
+    }
+}
+
+/// In-memory storage engine backed by a `ShardedMap`.
+pub struct MemoryStorage {
+    entries: Arc<ShardedMap<Entry>>,
+    aof: Arc<AppendLog>,
+    expiries: ExpiryQueue,
+    /// The most recently issued entry version.
+    version: Arc<AtomicU64>,
+    indexes: Arc<Indexes>,
+    /// Held while an index is created or dropped, so the log records them in the order
+    /// they are applied.
+    index_changes: Mutex<()>,
+    capacity: Arc<Capacity>,
+    /// Approximate bytes taken up by the entries of this engine alone.
+    used: AtomicUsize,
+    log: Logger,
+}
+
+impl MemoryStorage {
+    /// Restores the contents from the append-only log or the newest snapshot,
+    /// whichever is configured, and starts the background tasks.
+    pub fn open(config: &Config, capacity: Arc<Capacity>, log: &Logger) -> io::Result<Arc<Self>> {
+        let ((entries, version, indexes), aof) = match &config.aof_path {
+            Some(path) => {
+                let restored = aof::replay(path)?;
+                (restored, AppendLog::open(path, config.aof_fsync)?)
+            }
+            None => {
+                // Without a log, the newest snapshot is the most recent copy of the data.
+                let restored = match &config.snapshot_dir {
+                    Some(dir) => snapshot::load_latest(dir, log)?,
+                    None => None,
+                };
+                (restored.unwrap_or_default(), AppendLog::disabled())
+            }
+        };
+
+        let storage = MemoryStorage::new(entries, version, aof, Arc::clone(&capacity), log.clone());
+        let storage = Arc::new(storage);
+        capacity.register(Arc::downgrade(&storage) as Weak<dyn Evictable>);
+        // Indexes created at runtime are restored first, so they stay persisted if they are
+        // also in the settings.
+        let restored = indexes.iter().map(|name| (name, true));
+        for (name, persisted) in restored.chain(config.indexes.iter().map(|name| (name, false))) {
+            let path = Path::parse(name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid index path: {}", name))
+            })?;
+            storage.indexes.create(name, path, persisted, &storage.entries);
+        }
+        for shard in storage.entries.shards() {
+            for (key, entry) in shard.read().iter() {
+                if let Some(deadline) = entry.expiry {
+                    storage.expiries.schedule(key, deadline);
+                }
+            }
+        }
+
+        if storage.aof.policy() == FsyncPolicy::EverySec {
+            task::spawn(aof::sync_every_second(Arc::clone(&storage.aof), log.clone()));
+        }
+        if storage.aof.is_enabled() {
+            task::spawn(
+                aof::rewrite_when_grown(
+                    Arc::clone(&storage.entries),
+                    Arc::clone(&storage.version),
+                    Arc::clone(&storage.indexes),
+                    Arc::clone(&storage.aof),
+                    config.aof_rewrite_percentage,
+                    config.aof_rewrite_min_size,
+                    log.clone()
+                )
+            );
+        }
+        if let Some(dir) = config.snapshot_dir.clone() {
+            let interval = Duration::from_secs(config.snapshot_interval.max(1));
+            task::spawn(
+                snapshot::save_periodically(
+                    Arc::clone(&storage.entries),
+                    Arc::clone(&storage.version),
+                    Arc::clone(&storage.indexes),
+                    dir,
+                    interval,
+                    config.snapshot_retain,
+                    log.clone()
+                )
+            );
+        }
+        task::spawn(Arc::clone(&storage).cleanup_expired_keys());
+        Ok(storage)
+    }
+
+    /// Creates a storage engine holding `entries` without starting any background tasks.
+    ///
+    /// Versions continue after `version`, the highest version issued before, or after the
+    /// highest version among `entries` if that is higher; entries restored from files
+    /// written before versions existed are given fresh ones.
+    pub fn new(
+        mut entries: HashMap<String, Entry>,
+        version: u64,
+        aof: AppendLog,
+        capacity: Arc<Capacity>,
+        log: Logger
+    ) -> Self {
+        let mut version = entries
+            .values()
+            .map(|entry| entry.version)
+            .fold(version, u64::max);
+        for entry in entries.values_mut().filter(|entry| entry.version == 0) {
+            version += 1;
+            entry.version = version;
+        }
+        let used = entries
+            .iter()
+            .map(|(key, entry)| eviction::size(key, &entry.value))
+            .sum();
+        capacity.add(used, entries.len());
+        MemoryStorage {
+            entries: Arc::new(entries.into_iter().collect()),
+            aof: Arc::new(aof),
+            expiries: ExpiryQueue::new(),
+            version: Arc::new(AtomicU64::new(version)),
+            indexes: Arc::new(Indexes::new()),
+            index_changes: Mutex::new(()),
+            capacity,
+            used: AtomicUsize::new(used),
+            log,
+        }
+    }
+
+    /// Issues the version of a new write.
+    fn next_version(&self) -> u64 {
+        self.version.fetch_add(1, AtomicOrdering::Relaxed) + 1
+    }
+
+    /// Removes the entry stored under `key` if it has expired, so reads never observe
+    /// a stale value between runs of `cleanup_expired_keys`.
+    fn evict_if_expired(&self, shard: &mut BTreeMap<String, Entry>, key: &str) {
+        let expired = shard.get(key).is_some_and(|entry| entry.is_expired(Instant::now()));
+        if expired {
+            // The deadline is already in the log, so a failed write here only delays the eviction on replay.
+            if let Err(e) = self.aof.append(&(Record::Expire { key: key.to_string() })) {
+                slog::error!(self.log, "Error writing append-only log: {:?}", e);
+            }
+            self.remove(shard, key);
+        }
+    }
+
+    /// Logs and inserts a new version of the entry under `key` into its locked `shard`.
+    fn store<'a>(
+        &self,
+        shard: &'a mut BTreeMap<String, Entry>,
+        key: &str,
+        value: Data,
+        expiry: ExpiryTime,
+        sliding: Option<Duration>
+    ) -> io::Result<&'a Entry> {
+        let access = accessed(shard.get(key));
+        let version = self.next_version();
+        let entry = Entry { value, expiry, logged_expiry: expiry, sliding, version, access };
+        self.aof.append(&Record::set(key, &entry))?;
+        Ok(self.insert(shard, key, entry))
+    }
+
+    /// Inserts `entry` under `key` into its locked `shard` and updates the indexes,
+    /// capacity usage and expiry schedule.
+    fn insert<'a>(&self, shard: &'a mut BTreeMap<String, Entry>, key: &str, entry: Entry) -> &'a Entry {
+        self.allocate(eviction::size(key, &entry.value), 1);
+        let expiry = entry.expiry;
+        let old = shard.insert(key.to_string(), entry);
+        if let Some(old) = &old {
+            self.release(eviction::size(key, &old.value), 1);
+        }
+        match expiry {
+            Some(deadline) => self.expiries.schedule(key, deadline),
+            // Only keys that had a deadline can be scheduled.
+            None if old.as_ref().is_some_and(|old| old.expiry.is_some()) => {
+                self.expiries.cancel(key);
+            }
+            None => {}
+        }
+        let new = &shard[key];
+        self.indexes.update(key, old.as_ref().map(|old| &old.value), Some(&new.value));
+        new
+    }
+
+    /// Removes `key` from its locked `shard` and updates the indexes and capacity usage.
+    fn remove(&self, shard: &mut BTreeMap<String, Entry>, key: &str) {
+        if let Some(old) = shard.remove(key) {
+            self.release(eviction::size(key, &old.value), 1);
+            self.indexes.update(key, Some(&old.value), None);
+            if old.expiry.is_some() {
+                self.expiries.cancel(key);
+            }
+        }
+    }
+
+    fn allocate(&self, bytes: usize, entries: usize) {
+        self.used.fetch_add(bytes, AtomicOrdering::Relaxed);
+        self.capacity.add(bytes, entries);
+    }
+
+    fn release(&self, bytes: usize, entries: usize) {
+        self.used.fetch_sub(bytes, AtomicOrdering::Relaxed);
+        self.capacity.sub(bytes, entries);
+    }
+
+    /// Removes keys as their deadlines come due.
+    ///
+    /// Due keys are expired in batches, yielding in between so a large number of keys
+    /// expiring at once does not monopolize the executor.
+    async fn cleanup_expired_keys(self: Arc<Self>) {
+        loop {
+            self.expiries.wait().await;
+
+            loop {
+                let due = self.expiries.pop_due(Instant::now(), EXPIRY_BATCH_SIZE);
+                if due.is_empty() {
+                    break;
+                }
+                for (deadline, key) in due {
+                    let mut shard = self.entries.write(&key);
+                    let Some(entry) = shard.get(&key) else {
+                        continue;
+                    };
+                    match entry.expiry {
+                        Some(expiry) if expiry == deadline => {
+                            if let Err(e) = self.aof.append(&(Record::Expire { key: key.clone() })) {
+                                slog::error!(self.log, "Error writing append-only log: {:?}", e);
+                            }
+                            self.remove(&mut shard, &key);
+                        }
+                        // Reads renew sliding expiries without scheduling them.
+                        Some(expiry) if expiry > deadline && entry.sliding.is_some() => {
+                            self.expiries.schedule(&key, expiry);
+                        }
+                        // Deadlines of keys that were since overwritten no longer match.
+                        _ => {}
+                    }
+                }
+                task::yield_now().await;
+            }
+        }
+    }
+}
+
+impl Evictable for MemoryStorage {
+    /// Samples keys starting at a random position.
+    fn sample(&self, policy: EvictionPolicy, now: Instant, keep: &[&str]) -> Option<(u64, String)> {
+        let shards = self.entries.shards();
+        let start = random() % shards.len();
+        let mut victim: Option<(u64, String)> = None;
+        let mut sampled = 0;
+        for i in 0..shards.len() {
+            let shard = shards[(start + i) % shards.len()].read();
+            if shard.is_empty() {
+                continue;
+            }
+            let skip = random() % shard.len();
+            for (key, entry) in shard.iter().skip(skip).chain(shard.iter().take(skip)) {
+                if keep.contains(&key.as_str()) {
+                    continue;
+                }
+                let Some(rank) = policy.rank(entry, now) else {
+                    continue;
+                };
+                if victim.as_ref().is_none_or(|(lowest, _)| rank < *lowest) {
+                    victim = Some((rank, key.clone()));
+                }
+                sampled += 1;
+                if sampled == SAMPLES {
+                    return victim;
+                }
+            }
+        }
+        victim
+    }
+
+    fn evict(&self, key: &str) -> io::Result<()> {
+        let mut shard = self.entries.write(key);
+        if shard.contains_key(key) {
+            self.aof.append(&(Record::Delete { key: key.to_string() }))?;
+            self.remove(&mut shard, key);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Option<Entry> {
+        let entry = self.peek(key)?;
+        let Some(sliding) = entry.sliding else {
+            return Some(entry);
+        };
+
+        let mut shard = self.entries.write(key);
+        self.evict_if_expired(&mut shard, key);
+        let entry = shard.get_mut(key)?;
+        // A TTL too long to set a deadline with leaves the current one in place.
+        let Some(deadline) = ttl::deadline(Instant::now(), sliding) else {
+            return Some(entry.clone());
+        };
+        // Renewals are only logged once the logged deadline falls behind by half the TTL, so
+        // frequent reads do not each write to the log. After a restart, a key may expire up
+        // to that much earlier than it would have.
+        let behind = entry.logged_expiry.is_none_or(|logged| {
+            deadline.saturating_duration_since(logged) >= sliding / 2
+        });
+        if behind {
+            let expires_at = to_unix_millis(deadline);
+            let record = Record::Touch { key: key.to_string(), expires_at };
+            // Failing to log the renewal only shortens the TTL after a restart, so the read
+            // goes ahead.
+            match self.aof.append(&record) {
+                Ok(()) => entry.logged_expiry = Some(deadline),
+                Err(e) => slog::error!(self.log, "Error writing append-only log: {:?}", e),
+            }
+        }
+        // The expiry task reschedules the entry once its previous deadline comes due, so
+        // frequent reads do not fill the expiry queue.
+        entry.expiry = Some(deadline);
+        Some(entry.clone())
+    }
+
+    fn peek(&self, key: &str) -> Option<Entry> {
+        {
+            let shard = self.entries.read(key);
+            match shard.get(key) {
+                Some(entry) if !entry.is_expired(Instant::now()) => {
+                    entry.access.touch();
+                    return Some(entry.clone());
+                }
+                Some(_) => {}
+                None => {
+                    return None;
+                }
+            }
+        }
+        self.evict_if_expired(&mut self.entries.write(key), key);
+        None
+    }
+
+    fn set(
+        &self,
+        key: &str,
+        value: Data,
+        expiry: ExpiryTime,
+        sliding: Option<Duration>,
+        precondition: &Precondition
+    ) -> Result<u64, WriteError> {
+        let entry = self.update(key, precondition, &mut (|_| Ok((value.clone(), expiry, sliding))))?;
+        Ok(entry.version)
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        precondition: &Precondition,
+        update: &mut Update
+    ) -> Result<Entry, WriteError> {
+        loop {
+            let mut shard = self.entries.write(key);
+            self.evict_if_expired(&mut shard, key);
+            let current = shard.get(key);
+            precondition.check(current.map(|entry| entry.version))?;
+            let (value, expiry, sliding) = update(current)?;
+            self.capacity.limits.check_key(key)?;
+            self.capacity.limits.check_value(&value)?;
+            let size = |value| eviction::size(key, value);
+            let growth = size(&value).saturating_sub(current.map_or(0, |entry| size(&entry.value)));
+            let added = current.is_none() as usize;
+            if self.capacity.is_exceeded(growth, added) {
+                // Evicting takes the locks of other shards, so this one is released and the
+                // update derived again afterwards. The key itself is spared, so the update
+                // is derived from the same entry unless another write changed it meanwhile.
+                drop(shard);
+                self.capacity.reclaim(growth, added, self, &[key])?;
+                continue;
+            }
+            return Ok(self.store(&mut shard, key, value, expiry, sliding)?.clone());
+        }
+    }
+
+    fn delete(&self, key: &str, precondition: &Precondition) -> Result<bool, WriteError> {
+        let mut shard = self.entries.write(key);
+        self.evict_if_expired(&mut shard, key);
+        precondition.check(shard.get(key).map(|entry| entry.version))?;
+        if !shard.contains_key(key) {
+            return Ok(false);
+        }
+        self.aof.append(&(Record::Delete { key: key.to_string() }))?;
+        self.remove(&mut shard, key);
+        Ok(true)
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<Entry>> {
+        let shards = self.entries.read_many(keys.iter().copied());
+        let now = Instant::now();
+        // Expired entries are left for the expiry task, as evicting them would need the write locks.
+        keys.iter()
+            .map(|key| {
+                shards
+                    .shard(key)
+                    .get(*key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .inspect(|entry| entry.access.touch())
+                    .cloned()
+            })
+            .collect()
+    }
+
+    fn set_many(&self, entries: Vec<(String, Data, ExpiryTime)>) -> Result<Vec<u64>, WriteError> {
+        for (key, value, _) in &entries {
+            self.capacity.limits.check_key(key)?;
+            self.capacity.limits.check_value(value)?;
+        }
+        let mut shards = loop {
+            let shards = self.entries.write_many(entries.iter().map(|(key, _, _)| key.as_str()));
+            let (mut growth, mut added) = (0, 0);
+            for (key, value, _) in &entries {
+                let current = shards.shard(key).get(key);
+                let size = |value| eviction::size(key, value);
+                growth += size(value).saturating_sub(current.map_or(0, |entry| size(&entry.value)));
+                added += current.is_none() as usize;
+            }
+            if !self.capacity.is_exceeded(growth, added) {
+                break shards;
+            }
+            drop(shards);
+            let keys: Vec<&str> = entries.iter().map(|(key, _, _)| key.as_str()).collect();
+            self.capacity.reclaim(growth, added, self, &keys)?;
+        };
+        let now = Instant::now();
+        let entries: Vec<(String, Entry)> = entries
+            .into_iter()
+            .map(|(key, value, expiry)| {
+                let version = self.next_version();
+                let current = shards.shard(&key).get(&key).filter(|entry| !entry.is_expired(now));
+                let access = accessed(current);
+                let logged_expiry = expiry;
+                let entry = Entry { value, expiry, logged_expiry, sliding: None, version, access };
+                (key, entry)
+            })
+            .collect();
+        let records = entries
+            .iter()
+            .map(|(key, entry)| Record::set(key, entry))
+            .collect();
+        self.aof.append(&(Record::Batch { records }))?;
+        let mut versions = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            versions.push(entry.version);
+            self.insert(shards.shard_mut(&key), &key, entry);
+        }
+        Ok(versions)
+    }
+
+    fn delete_many(&self, keys: &[&str], atomic: bool) -> Result<Vec<bool>, WriteError> {
+        let mut shards = self.entries.write_many(keys.iter().copied());
+        let mut seen = HashSet::new();
+        let existed: Vec<bool> = keys
+            .iter()
+            .map(|key| {
+                let shard = shards.shard_mut(key);
+                self.evict_if_expired(shard, key);
+                seen.insert(*key) && shard.contains_key(*key)
+            })
+            .collect();
+        if atomic && existed.contains(&false) {
+            return Ok(existed);
+        }
+
+        let records: Vec<Record> = keys
+            .iter()
+            .zip(&existed)
+            .filter(|(_, existed)| **existed)
+            .map(|(key, _)| Record::Delete { key: key.to_string() })
+            .collect();
+        if !records.is_empty() {
+            self.aof.append(&(Record::Batch { records }))?;
+        }
+        for key in keys {
+            self.remove(shards.shard_mut(key), key);
+        }
+        Ok(existed)
+    }
+
+    fn create_index(&self, name: &str, path: Path) -> io::Result<bool> {
+        let _changes = self.index_changes.lock().unwrap();
+        if self.indexes.contains(name) {
+            return Ok(false);
+        }
+        self.aof.append(&(Record::CreateIndex { path: name.to_string() }))?;
+        Ok(self.indexes.create(name, path, true, &self.entries))
+    }
+
+    fn drop_index(&self, name: &str) -> io::Result<bool> {
+        let _changes = self.index_changes.lock().unwrap();
+        if !self.indexes.contains(name) {
+            return Ok(false);
+        }
+        self.aof.append(&(Record::DropIndex { path: name.to_string() }))?;
+        Ok(Indexes::drop(&self.indexes, name))
+    }
+
+    fn indexes(&self) -> Vec<String> {
+        self.indexes.paths()
+    }
+
+    fn lookup(&self, name: &str, value: &Value) -> Option<Vec<(String, Entry)>> {
+        let keys = self.indexes.lookup(name, value)?;
+        // The entries may have changed since the index was read, so they are checked again.
+        let entries = keys
+            .into_iter()
+            .filter_map(|key| {
+                let entry = self.peek(&key)?;
+                self.indexes.matches(name, &entry, value).then_some((key, entry))
+            })
+            .collect();
+        Some(entries)
+    }
+
+    fn memory(&self) -> usize {
+        self.used.load(AtomicOrdering::Relaxed)
+    }
+
+    fn flush(&self) -> io::Result<usize> {
+        // Holding every shard keeps writes from landing on either side of the logged flush.
+        let mut shards: Vec<_> = self.entries
+            .shards()
+            .iter()
+            .map(|shard| shard.write())
+            .collect();
+        self.aof.append(&Record::Flush)?;
+        let now = Instant::now();
+        let mut count = 0;
+        for shard in shards.iter_mut() {
+            count += shard
+                .values()
+                .filter(|entry| !entry.is_expired(now))
+                .count();
+            let keys: Vec<String> = shard.keys().cloned().collect();
+            for key in keys {
+                self.remove(shard, &key);
+            }
+        }
+        Ok(count)
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(&str, &Entry)) {
+        let now = Instant::now();
+        for shard in self.entries.shards() {
+            for (key, entry) in shard.read().iter() {
+                if !entry.is_expired(now) {
+                    visit(key, entry);
+                }
+            }
+        }
+    }
+
+    /// Reads the keys after `after` from each shard in order, stopping at the first key
+    /// past the page, so a page costs about `limit` entries per shard however many
+    /// keys there are.
+    fn scan_page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+        filter: &dyn Fn(&str, &Entry) -> bool
+    ) -> Vec<(String, Entry)> {
+        let now = Instant::now();
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        // Max-heap holding the smallest `limit` keys seen so far.
+        let mut page: BinaryHeap<ByKey> = BinaryHeap::with_capacity(limit + 1);
+        for shard in self.entries.shards() {
+            let shard = shard.read();
+            for (key, entry) in shard.range::<str, _>((start, Bound::Unbounded)) {
+                let largest = page.peek().map(|largest| largest.0.as_str());
+                if page.len() == limit && largest.is_none_or(|largest| key.as_str() >= largest) {
+                    break;
+                }
+                if entry.is_expired(now) || !filter(key, entry) {
+                    continue;
+                }
+                page.push(ByKey(key.clone(), entry.clone()));
+                if page.len() > limit {
+                    page.pop();
+                }
+            }
+        }
+        page.into_sorted_vec()
+            .into_iter()
+            .map(|ByKey(key, entry)| (key, entry))
+            .collect()
+    }
+
+    fn compact(&self) -> io::Result<()> {
+        if !self.aof.is_enabled() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "append-only log is disabled"));
+        }
+        self.aof.rewrite(&self.entries, &self.version, &self.indexes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+    use serde_json::json;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn capacity(
+        max_entries: usize,
+        max_memory: usize,
+        eviction_policy: EvictionPolicy
+    ) -> Arc<Capacity> {
+        let limits = Limits {
+            max_key_length: 0,
+            max_value_size: 0,
+            max_entries,
+            max_memory,
+            eviction_policy,
+        };
+        Arc::new(Capacity::new(limits))
+    }
+
+    fn discard() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// Returns an engine without a log or background tasks, counting towards `capacity`.
+    fn storage(capacity: &Arc<Capacity>) -> Arc<MemoryStorage> {
+        let aof = AppendLog::disabled();
+        let storage = MemoryStorage::new(HashMap::new(), 0, aof, Arc::clone(capacity), discard());
+        let storage = Arc::new(storage);
+        capacity.register(Arc::downgrade(&storage) as Weak<dyn Evictable>);
+        storage
+    }
+
+    fn data(value: Value) -> Data {
+        Data::Json(Arc::new(value))
+    }
+
+    fn set(storage: &MemoryStorage, key: &str, expiry: ExpiryTime) -> Result<u64, WriteError> {
+        storage.set(key, data(json!(key)), expiry, None, &Precondition::default())
+    }
+
+    fn keys(storage: &MemoryStorage) -> Vec<String> {
+        let mut keys = Vec::new();
+        storage.scan(&mut (|key, _| keys.push(key.to_string())));
+        keys.sort();
+        keys
+    }
+
+    /// Lets enough time pass for accesses to be told apart by recency.
+    fn tick() {
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    /// Returns the path of a log in a new, empty temporary directory.
+    fn log_path() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, AtomicOrdering::Relaxed);
+        let name = format!("micro_kv-engine-{}-{}", std::process::id(), count);
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("appendonly.aof")
+    }
+
+    fn set_sliding(storage: &MemoryStorage, key: &str, sliding: Duration) {
+        let expiry = ttl::deadline(Instant::now(), sliding);
+        let precondition = Precondition::default();
+        storage.set(key, data(json!(key)), expiry, Some(sliding), &precondition).unwrap();
+    }
+
+    fn is_stored(storage: &MemoryStorage, key: &str) -> bool {
+        storage.entries.read(key).contains_key(key)
+    }
+
+    #[test]
+    fn noeviction_refuses_writes_beyond_the_limit() {
+        let capacity = capacity(2, 0, EvictionPolicy::NoEviction);
+        let storage = storage(&capacity);
+        set(&storage, "a", None).unwrap();
+        set(&storage, "b", None).unwrap();
+        assert!(matches!(set(&storage, "c", None), Err(WriteError::OutOfMemory)));
+        set(&storage, "a", None).unwrap();
+        assert_eq!(keys(&storage), ["a", "b"]);
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_key() {
+        let capacity = capacity(2, 0, EvictionPolicy::AllkeysLru);
+        let storage = storage(&capacity);
+        set(&storage, "a", None).unwrap();
+        tick();
+        set(&storage, "b", None).unwrap();
+        tick();
+        storage.get("a").unwrap();
+        tick();
+        set(&storage, "c", None).unwrap();
+        assert_eq!(keys(&storage), ["a", "c"]);
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used_key() {
+        let capacity = capacity(2, 0, EvictionPolicy::AllkeysLfu);
+        let storage = storage(&capacity);
+        set(&storage, "a", None).unwrap();
+        set(&storage, "b", None).unwrap();
+        for _ in 0..3 {
+            storage.get("a").unwrap();
+        }
+        set(&storage, "c", None).unwrap();
+        assert_eq!(keys(&storage), ["a", "c"]);
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_keys_expiring_soonest() {
+        let capacity = capacity(3, 0, EvictionPolicy::VolatileTtl);
+        let storage = storage(&capacity);
+        let now = Instant::now();
+        set(&storage, "a", None).unwrap();
+        set(&storage, "b", Some(now + Duration::from_secs(3600))).unwrap();
+        set(&storage, "c", Some(now + Duration::from_secs(60))).unwrap();
+        set(&storage, "d", None).unwrap();
+        assert_eq!(keys(&storage), ["a", "b", "d"]);
+        set(&storage, "e", None).unwrap();
+        assert_eq!(keys(&storage), ["a", "d", "e"]);
+        // Keys without a TTL are never evicted.
+        assert!(matches!(set(&storage, "f", None), Err(WriteError::OutOfMemory)));
+    }
+
+    #[test]
+    fn eviction_spans_engines_sharing_capacity() {
+        let capacity = capacity(2, 0, EvictionPolicy::AllkeysLru);
+        let (first, second) = (storage(&capacity), storage(&capacity));
+        set(&first, "x", None).unwrap();
+        tick();
+        set(&second, "y", None).unwrap();
+        tick();
+        set(&second, "z", None).unwrap();
+        assert!(keys(&first).is_empty());
+        assert_eq!(keys(&second), ["y", "z"]);
+    }
+
+    #[test]
+    fn growing_a_key_evicts_others_but_never_the_key_itself() {
+        let small = data(json!("x"));
+        let large = data(json!("x".repeat(100)));
+        let room = eviction::size("a", &small) + eviction::size("b", &small) + 50;
+        let capacity = capacity(0, room, EvictionPolicy::AllkeysLru);
+        let storage = storage(&capacity);
+        let precondition = Precondition::default();
+        storage.set("a", small.clone(), None, None, &precondition).unwrap();
+        tick();
+        storage.set("b", small, None, None, &precondition).unwrap();
+        tick();
+        storage.get("b").unwrap();
+        tick();
+
+        // `a` is the least recently used key, but it is the one being updated.
+        let entry = storage
+            .update(
+                "a",
+                &precondition,
+                &mut (|current| {
+                    current.ok_or(WriteError::KeyNotFound)?;
+                    Ok((large.clone(), None, None))
+                })
+            )
+            .unwrap();
+        assert_eq!(entry.version, 3);
+        assert_eq!(keys(&storage), ["a"]);
+    }
+
+    #[test]
+    fn pages_visit_every_key_once_in_order_despite_writes() {
+        let storage = storage(&capacity(0, 0, EvictionPolicy::NoEviction));
+        for i in 0..50 {
+            set(&storage, &format!("k{:02}", i), None).unwrap();
+        }
+        let mut seen: Vec<String> = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let page = storage.scan_page(after.as_deref(), 7, &(|_, _| true));
+            // Keys written before and after the cursor, and deleted ahead of it.
+            set(&storage, &format!("a{}", seen.len()), None).unwrap();
+            set(&storage, &format!("z{}", seen.len()), None).unwrap();
+            storage.delete(&format!("z{}", seen.len()), &Precondition::default()).unwrap();
+            let full = page.len() == 7;
+            seen.extend(page.into_iter().map(|(key, _)| key));
+            if !full {
+                break;
+            }
+            after = seen.last().cloned();
+        }
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
+        let expected: Vec<String> = (0..50).map(|i| format!("k{:02}", i)).collect();
+        let visited: Vec<String> = seen.into_iter().filter(|key| key.starts_with('k')).collect();
+        assert_eq!(visited, expected);
+    }
+
+    #[test]
+    fn pages_skip_filtered_and_expired_entries() {
+        let storage = storage(&capacity(0, 0, EvictionPolicy::NoEviction));
+        for key in ["a", "b", "c", "d", "e"] {
+            set(&storage, key, None).unwrap();
+        }
+        set(&storage, "bb", Some(Instant::now())).unwrap();
+        let page = storage.scan_page(Some("a"), 2, &(|key, _| key != "c"));
+        let keys: Vec<String> = page.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["b", "d"]);
+        assert!(storage.scan_page(None, 0, &(|_, _| true)).is_empty());
+    }
+
+    #[test]
+    fn sliding_renewals_are_logged_at_most_every_half_ttl() {
+        let path = log_path();
+        let aof = AppendLog::open(&path, FsyncPolicy::Never).unwrap();
+        let capacity = capacity(0, 0, EvictionPolicy::NoEviction);
+        let storage = MemoryStorage::new(HashMap::new(), 0, aof, capacity, discard());
+        let touches = || {
+            fs::read_to_string(&path).unwrap().matches(r#""op":"touch""#).count()
+        };
+        set_sliding(&storage, "a", Duration::from_millis(200));
+        for _ in 0..10 {
+            storage.get("a").unwrap();
+        }
+        assert_eq!(touches(), 0);
+
+        thread::sleep(Duration::from_millis(120));
+        storage.get("a").unwrap();
+        storage.get("a").unwrap();
+        assert_eq!(touches(), 1);
+    }
+
+    #[test]
+    fn expiry_task_reschedules_renewed_sliding_keys() {
+        let storage = storage(&capacity(0, 0, EvictionPolicy::NoEviction));
+        task::spawn(Arc::clone(&storage).cleanup_expired_keys());
+        set_sliding(&storage, "a", Duration::from_millis(200));
+        set_sliding(&storage, "b", Duration::from_millis(200));
+
+        thread::sleep(Duration::from_millis(120));
+        storage.get("a").unwrap();
+        // `b` comes due first; `a` is renewed past its first deadline.
+        thread::sleep(Duration::from_millis(150));
+        assert!(is_stored(&storage, "a"));
+        assert!(!is_stored(&storage, "b"));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!is_stored(&storage, "a"));
+    }
+
+    #[test]
+    fn indexes_follow_overwrites_deletes_expiry_and_flush() {
+        let storage = storage(&capacity(0, 0, EvictionPolicy::NoEviction));
+        task::spawn(Arc::clone(&storage).cleanup_expired_keys());
+        let write = |key: &str, role: &str, expiry: ExpiryTime| {
+            let value = data(json!({ "role": role }));
+            storage.set(key, value, expiry, None, &Precondition::default()).unwrap();
+        };
+        let indexed = |role: &str| storage.indexes.lookup("$.role", &json!(role)).unwrap();
+        write("a", "admin", None);
+        assert!(storage.create_index("$.role", Path::parse("$.role").unwrap()).unwrap());
+        write("b", "admin", None);
+        write("c", "user", Some(Instant::now() + Duration::from_millis(20)));
+        assert_eq!(indexed("admin"), ["a", "b"]);
+
+        write("a", "user", None);
+        assert_eq!(indexed("admin"), ["b"]);
+        assert_eq!(indexed("user"), ["a", "c"]);
+
+        storage.delete("b", &Precondition::default()).unwrap();
+        assert!(indexed("admin").is_empty());
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(indexed("user"), ["a"]);
+
+        assert_eq!(storage.flush().unwrap(), 1);
+        assert!(indexed("user").is_empty());
+        assert_eq!(storage.indexes(), ["$.role"]);
+    }
+
+    #[test]
+    fn delete_many_reports_repeated_keys_missing() {
+        let storage = storage(&capacity(0, 0, EvictionPolicy::NoEviction));
+        set(&storage, "a", None).unwrap();
+        set(&storage, "b", None).unwrap();
+        assert_eq!(storage.delete_many(&["a", "b", "a"], true).unwrap(), [true, true, false]);
+        assert_eq!(keys(&storage), ["a", "b"]);
+        assert_eq!(storage.delete_many(&["a", "b", "a"], false).unwrap(), [true, true, false]);
+        assert!(keys(&storage).is_empty());
+        assert_eq!(storage.memory(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+use rocket::http::{ Header, Status };
+use rocket::request::{ Outcome, Request };
+use rocket::response::{ self, Responder };
+use rocket::serde::json::Json;
+use serde::Serialize;
+
+/// Errors returned by the request handlers, rendered as a JSON body with a matching status code.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// The requested key does not exist.
+    KeyNotFound(String),
+    /// The requested path does not exist within the value of a key.
+    PathNotFound(String),
+    /// There is no index on the requested path.
+    IndexNotFound(String),
+    /// The requested namespace does not exist.
+    NamespaceNotFound(String),
+    /// The request is malformed, e.g. an unparsable query parameter.
+    BadRequest(String),
+    /// The request lacks valid credentials.
+    Unauthorized(String),
+    /// The credentials of the request do not allow it.
+    Forbidden(String),
+    /// The request conflicts with the current state of the server.
+    Conflict(String),
+    /// A conditional request header does not match the current version of the entry.
+    PreconditionFailed(String),
+    /// The value to store is larger than allowed.
+    PayloadTooLarge(String),
+    /// The request body has a media type the endpoint does not accept.
+    UnsupportedMediaType(String),
+    /// The write does not fit within the memory limit.
+    InsufficientStorage(String),
+    /// The server failed to complete the request.
+    Internal(String),
+    /// An error raised by Rocket itself, described by its status alone.
+    Rocket(Status),
+}
+
+/// Uniform body of every error response.
+#[derive(Serialize)]
+pub struct ErrorBody {
+    error: &'static str,
+    status: String,
+}
+
+impl Error {
+    pub fn status(&self) -> Status {
+        match self {
+            | Error::KeyNotFound(_)
+            | Error::PathNotFound(_)
+            | Error::IndexNotFound(_)
+            | Error::NamespaceNotFound(_) => Status::NotFound,
+            Error::BadRequest(_) => Status::BadRequest,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::Conflict(_) => Status::Conflict,
+            Error::PreconditionFailed(_) => Status::PreconditionFailed,
+            Error::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            Error::UnsupportedMediaType(_) => Status::UnsupportedMediaType,
+            Error::InsufficientStorage(_) => Status::InsufficientStorage,
+            Error::Internal(_) => Status::InternalServerError,
+            Error::Rocket(status) => *status,
+        }
+    }
+
+    pub fn message(self) -> String {
+        match self {
+            Error::KeyNotFound(key) => format!("Key not found: {}", key),
+            Error::PathNotFound(path) => format!("Path not found: {}", path),
+            Error::IndexNotFound(path) => format!("Index not found: {}", path),
+            Error::NamespaceNotFound(name) => format!("Namespace not found: {}", name),
+            | Error::BadRequest(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::Conflict(message)
+            | Error::PreconditionFailed(message)
+            | Error::PayloadTooLarge(message)
+            | Error::UnsupportedMediaType(message)
+            | Error::InsufficientStorage(message)
+            | Error::Internal(message) => message,
+            Error::Rocket(status) => status.reason_lossy().to_string(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let mut response = (status, body(status, self.message())).respond_to(req)?;
+        if status == Status::Unauthorized {
+            response.set_header(Header::new("WWW-Authenticate", "Bearer"));
+        }
+        Ok(response)
+    }
+}
+
+fn body(status: Status, message: String) -> Json<ErrorBody> {
+    Json(ErrorBody {
+        error: status.reason_lossy(),
+        status: message,
+    })
+}
+
+/// The error a request guard failed with, kept for the catcher.
+struct GuardError(Option<Error>);
+
+/// Fails a request guard with `error`, which the catcher renders like a handler error.
+pub fn fail<T>(req: &Request<'_>, error: Error) -> Outcome<T, Error> {
+    req.local_cache(|| GuardError(Some(error.clone())));
+    Outcome::Failure((error.status(), error))
+}
+
+/// Renders errors raised by Rocket itself, such as unparsable JSON bodies or unknown routes,
+/// and by failing request guards, with the same body as handler errors.
+#[catch(default)]
+pub fn default_catcher(status: Status, req: &Request) -> Error {
+    req.local_cache(|| GuardError(None)).0.clone().unwrap_or(Error::Rocket(status))
+}
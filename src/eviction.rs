@@ -0,0 +1,136 @@
+use crate::engine::{ Data, Entry };
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use std::io;
+use std::sync::atomic::{ AtomicU32, AtomicU64, Ordering };
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Number of entries sampled to pick each key to evict.
+pub const SAMPLES: usize = 16;
+/// Approximate bookkeeping cost of an entry beyond its key and value, in bytes.
+const ENTRY_OVERHEAD: usize = 96;
+/// Idle time after which the access frequency of an entry is halved, in milliseconds.
+const FREQUENCY_HALF_LIFE: u64 = 60_000;
+
+/// What to do when a write would exceed the entry or memory limit, selected with
+/// `eviction_policy`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Refuse the write.
+    #[default]
+    #[serde(rename = "noeviction")]
+    NoEviction,
+    /// Evict the least recently used keys.
+    AllkeysLru,
+    /// Evict the least frequently used keys.
+    AllkeysLfu,
+    /// Evict the keys with a TTL that expire soonest.
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Ranks `entry` as a candidate for eviction at `now`, lower ranks being evicted first,
+    /// or returns `None` if the policy never evicts it.
+    pub fn rank(self, entry: &Entry, now: Instant) -> Option<u64> {
+        match self {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllkeysLru => Some(entry.access.last()),
+            EvictionPolicy::AllkeysLfu => Some(entry.access.frequency(millis(now))),
+            EvictionPolicy::VolatileTtl => {
+                entry.expiry.map(|expiry| expiry.saturating_duration_since(now).as_millis() as u64)
+            }
+        }
+    }
+}
+
+/// A storage engine whose entries count towards a shared `Capacity`, so writes to any engine
+/// sharing it can evict them.
+pub trait Evictable: Send + Sync {
+    /// Returns the lowest ranked key by `policy` among a sample of entries other than those
+    /// under `keep`, with its rank, or `None` if the policy may evict none of them.
+    fn sample(&self, policy: EvictionPolicy, now: Instant, keep: &[&str]) -> Option<(u64, String)>;
+
+    /// Removes `key` to make room, if it still exists.
+    fn evict(&self, key: &str) -> io::Result<()>;
+}
+
+/// When an entry was last written or read, and roughly how often, for eviction.
+#[derive(Debug)]
+pub struct Access {
+    /// Milliseconds since the server started.
+    last: AtomicU64,
+    /// Reads and writes, halved for every `FREQUENCY_HALF_LIFE` the entry is idle.
+    count: AtomicU32,
+}
+
+impl Access {
+    /// Records a first access now.
+    pub fn new() -> Self {
+        Access { last: AtomicU64::new(millis(Instant::now())), count: AtomicU32::new(1) }
+    }
+
+    /// Records another access now. Racing accesses may be counted once, which is fine
+    /// for an approximation.
+    pub fn touch(&self) {
+        let now = millis(Instant::now());
+        let count = self.frequency(now).saturating_add(1);
+        self.count.store(count.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> u64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    fn frequency(&self, now: u64) -> u64 {
+        let halvings = now.saturating_sub(self.last()) / FREQUENCY_HALF_LIFE;
+        (self.count.load(Ordering::Relaxed) as u64).checked_shr(halvings as u32).unwrap_or(0)
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::new()
+    }
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Access {
+            last: AtomicU64::new(self.last()),
+            count: AtomicU32::new(self.count.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Returns the approximate number of bytes an entry under `key` holding `value` takes up.
+pub fn size(key: &str, value: &Data) -> usize {
+    let value = match value {
+        Data::Json(value) => json_size(value),
+        Data::Bytes { content_type, bytes } => content_type.len() + bytes.len(),
+    };
+    ENTRY_OVERHEAD + key.len() + value
+}
+
+fn json_size(value: &Value) -> usize {
+    let nested = match value {
+        Value::String(string) => string.len(),
+        Value::Array(items) => items.iter().map(json_size).sum(),
+        Value::Object(members) => {
+            members
+                .iter()
+                .map(|(name, value)| name.len() + json_size(value))
+                .sum()
+        }
+        _ => 0,
+    };
+    std::mem::size_of::<Value>() + nested
+}
+
+/// Returns the milliseconds between the first call and `instant`.
+fn millis(instant: Instant) -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    instant.saturating_duration_since(*START.get_or_init(Instant::now)).as_millis() as u64
+}
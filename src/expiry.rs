@@ -0,0 +1,189 @@
+use async_std::channel::{ self, Receiver, Sender };
+use async_std::future;
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, HashMap };
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Stale heap entries tolerated beyond the live ones before the heap is rebuilt.
+const STALE_SLACK: usize = 1024;
+
+/// Min-heap of key deadlines that wakes the expiry task whenever an earlier deadline is scheduled.
+///
+/// Only the latest deadline of each key is kept. Rescheduling a key leaves its previous
+/// deadline in the heap as a stale entry, skipped when it comes due, and the heap is rebuilt
+/// once stale entries outnumber live ones, so memory is bounded by the number of keys
+/// rather than the rate at which they are written.
+pub struct ExpiryQueue {
+    deadlines: Mutex<Deadlines>,
+    wakeup_tx: Sender<()>,
+    wakeup_rx: Receiver<()>,
+}
+
+#[derive(Default)]
+struct Deadlines {
+    heap: BinaryHeap<Reverse<(Instant, String)>>,
+    /// The deadline each key is scheduled for; heap entries that differ are stale.
+    latest: HashMap<String, Instant>,
+}
+
+impl Deadlines {
+    /// Rebuilds the heap from the latest deadlines if it holds too many stale entries.
+    fn compact_if_stale(&mut self) {
+        if self.heap.len() > 2 * self.latest.len() + STALE_SLACK {
+            self.heap = self.latest
+                .iter()
+                .map(|(key, deadline)| Reverse((*deadline, key.clone())))
+                .collect();
+        }
+    }
+}
+
+impl ExpiryQueue {
+    pub fn new() -> Self {
+        let (wakeup_tx, wakeup_rx) = channel::bounded(1);
+        ExpiryQueue {
+            deadlines: Mutex::new(Deadlines::default()),
+            wakeup_tx,
+            wakeup_rx,
+        }
+    }
+
+    /// Schedules `key` to expire at `deadline`, replacing any deadline it was scheduled for.
+    pub fn schedule(&self, key: &str, deadline: Instant) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if deadlines.latest.insert(key.to_string(), deadline) == Some(deadline) {
+            return;
+        }
+        let is_earliest = deadlines.heap.peek().is_none_or(|Reverse((next, _))| deadline < *next);
+        deadlines.heap.push(Reverse((deadline, key.to_string())));
+        deadlines.compact_if_stale();
+        if is_earliest {
+            // A full channel already has a wakeup pending, so the error can be ignored.
+            let _ = self.wakeup_tx.try_send(());
+        }
+    }
+
+    /// Unschedules `key`, if it was scheduled.
+    pub fn cancel(&self, key: &str) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if deadlines.latest.remove(key).is_some() {
+            deadlines.compact_if_stale();
+        }
+    }
+
+    /// Removes and returns up to `limit` deadlines that are due at `now`.
+    pub fn pop_due(&self, now: Instant, limit: usize) -> Vec<(Instant, String)> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let Deadlines { heap, latest } = &mut *deadlines;
+        let mut due = Vec::new();
+        while due.len() < limit {
+            match heap.peek() {
+                Some(Reverse((deadline, _))) if *deadline <= now => {
+                    let Reverse((deadline, key)) = heap.pop().unwrap();
+                    if latest.get(&key) == Some(&deadline) {
+                        latest.remove(&key);
+                        due.push((deadline, key));
+                    }
+                }
+                _ => {
+                    break;
+                }
+            }
+        }
+        due
+    }
+
+    /// Waits until the earliest deadline is due, or an earlier one is scheduled.
+    pub async fn wait(&self) {
+        let next = self.deadlines
+            .lock()
+            .unwrap()
+            .heap
+            .peek()
+            .map(|Reverse((deadline, _))| *deadline);
+        match next {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if !remaining.is_zero() {
+                    let _ = future::timeout(remaining, self.wakeup_rx.recv()).await;
+                }
+            }
+            None => {
+                let _ = self.wakeup_rx.recv().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use std::time::Duration;
+
+    fn after(now: Instant, secs: u64) -> Instant {
+        now + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn pops_due_deadlines_in_order() {
+        let queue = ExpiryQueue::new();
+        let now = Instant::now();
+        queue.schedule("c", after(now, 3));
+        queue.schedule("a", after(now, 1));
+        queue.schedule("b", after(now, 2));
+
+        assert!(queue.pop_due(now, 10).is_empty());
+        let due = queue.pop_due(after(now, 2), 1);
+        assert_eq!(due, [(after(now, 1), "a".to_string())]);
+        let due = queue.pop_due(after(now, 3), 10);
+        assert_eq!(due, [(after(now, 2), "b".to_string()), (after(now, 3), "c".to_string())]);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_deadline_of_a_key() {
+        let queue = ExpiryQueue::new();
+        let now = Instant::now();
+        queue.schedule("a", after(now, 2));
+        queue.schedule("a", after(now, 1));
+        queue.schedule("a", after(now, 1));
+        assert_eq!(queue.deadlines.lock().unwrap().heap.len(), 2);
+        assert_eq!(queue.pop_due(after(now, 5), 10), [(after(now, 1), "a".to_string())]);
+    }
+
+    #[test]
+    fn rescheduling_a_key_does_not_grow_the_heap() {
+        let queue = ExpiryQueue::new();
+        let now = Instant::now();
+        for i in 0..100_000 {
+            queue.schedule("hot", after(now, 3600 + i));
+        }
+        assert!(queue.deadlines.lock().unwrap().heap.len() <= 2 + STALE_SLACK);
+        assert_eq!(queue.pop_due(after(now, 200_000), 10).len(), 1);
+    }
+
+    #[test]
+    fn cancelled_keys_are_not_popped() {
+        let queue = ExpiryQueue::new();
+        let now = Instant::now();
+        queue.schedule("a", after(now, 1));
+        queue.schedule("b", after(now, 1));
+        queue.cancel("a");
+        assert_eq!(queue.pop_due(after(now, 1), 10), [(after(now, 1), "b".to_string())]);
+    }
+
+    #[test]
+    fn wait_returns_once_a_deadline_is_due() {
+        let queue = ExpiryQueue::new();
+        queue.schedule("a", Instant::now() + Duration::from_millis(10));
+        // Scheduling the earliest deadline also leaves a wakeup pending, so the first wait
+        // may return before the deadline.
+        let mut due = Vec::new();
+        while due.is_empty() {
+            task::block_on(queue.wait());
+            due = queue.pop_due(Instant::now(), 10);
+        }
+        assert_eq!(due.len(), 1);
+    }
+}
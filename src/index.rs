@@ -0,0 +1,187 @@
+use crate::engine::{ Data, Entry };
+use crate::query::Path;
+use micro_kv::storage::ShardedMap;
+use serde_json::Value;
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
+use std::sync::{ Mutex, RwLock };
+
+/// Secondary indexes mapping the values at a path within JSON documents to the keys holding them.
+///
+/// Callers update the indexes while holding the lock of the shard they modify, so an index
+/// never misses a write. Indexes are always locked after shards, never before.
+pub struct Indexes {
+    indexes: RwLock<HashMap<String, Mutex<Index>>>,
+}
+
+struct Index {
+    path: Path,
+    /// Keys by the canonical form of the indexed values.
+    keys: BTreeMap<String, BTreeSet<String>>,
+    /// False until the existing entries have been indexed.
+    ready: bool,
+    /// True if the index was created at runtime rather than from the `indexes` setting,
+    /// and so is kept by the append-only log and snapshots.
+    persisted: bool,
+}
+
+impl Index {
+    fn insert(&mut self, key: &str, value: &Data) {
+        for indexed in self.values(value) {
+            self.keys.entry(indexed).or_default().insert(key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str, value: &Data) {
+        for indexed in self.values(value) {
+            if let Some(keys) = self.keys.get_mut(&indexed) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&indexed);
+                }
+            }
+        }
+    }
+
+    /// Returns the canonical forms of the values at the indexed path within `value`.
+    fn values(&self, value: &Data) -> Vec<String> {
+        match value {
+            Data::Json(doc) => self.path.select(doc).into_iter().map(canonical).collect(),
+            Data::Bytes { .. } => Vec::new(),
+        }
+    }
+}
+
+impl Indexes {
+    pub fn new() -> Self {
+        Indexes { indexes: RwLock::new(HashMap::new()) }
+    }
+
+    /// Creates an index on the values at `path` and indexes the existing `entries`,
+    /// returning false if there already is one. Only `persisted` indexes are listed by
+    /// [`Indexes::persisted`].
+    pub fn create(
+        &self,
+        path: &str,
+        parsed: Path,
+        persisted: bool,
+        entries: &ShardedMap<Entry>
+    ) -> bool {
+        {
+            let mut indexes = self.indexes.write().unwrap();
+            if indexes.contains_key(path) {
+                return false;
+            }
+            let index = Index { path: parsed, keys: BTreeMap::new(), ready: false, persisted };
+            indexes.insert(path.to_string(), Mutex::new(index));
+        }
+        // Writes made meanwhile already update the index, and holding each shard's lock
+        // while indexing it keeps them from interleaving with the copy.
+        for shard in entries.shards() {
+            let shard = shard.read();
+            let indexes = self.indexes.read().unwrap();
+            let Some(index) = indexes.get(path) else {
+                // Dropped while being built.
+                return true;
+            };
+            let mut index = index.lock().unwrap();
+            for (key, entry) in shard.iter() {
+                index.insert(key, &entry.value);
+            }
+        }
+        if let Some(index) = self.indexes.read().unwrap().get(path) {
+            index.lock().unwrap().ready = true;
+        }
+        true
+    }
+
+    /// Drops the index on `path`, returning whether it existed.
+    pub fn drop(&self, path: &str) -> bool {
+        self.indexes.write().unwrap().remove(path).is_some()
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.indexes.read().unwrap().contains_key(path)
+    }
+
+    /// Returns the paths of all indexes, sorted.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.indexes.read().unwrap().keys().cloned().collect();
+        paths.sort_unstable();
+        paths
+    }
+
+    /// Returns the paths of the indexes created at runtime, which are not recreated from
+    /// the settings on startup.
+    pub fn persisted(&self) -> BTreeSet<String> {
+        self.indexes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, index)| index.lock().unwrap().persisted)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Updates every index for `key` changing from `old` to `new`, either of which is
+    /// `None` if the key does not exist before or after the change.
+    pub fn update(&self, key: &str, old: Option<&Data>, new: Option<&Data>) {
+        let indexes = self.indexes.read().unwrap();
+        for index in indexes.values() {
+            let mut index = index.lock().unwrap();
+            if let Some(old) = old {
+                index.remove(key, old);
+            }
+            if let Some(new) = new {
+                index.insert(key, new);
+            }
+        }
+    }
+
+    /// Returns the keys whose value at `path` equals `value`, in key order, or `None`
+    /// if there is no index on `path`.
+    pub fn lookup(&self, path: &str, value: &Value) -> Option<Vec<String>> {
+        let indexes = self.indexes.read().unwrap();
+        let index = indexes.get(path)?.lock().unwrap();
+        if !index.ready {
+            return None;
+        }
+        let keys = index.keys.get(&canonical(value));
+        Some(keys.map_or_else(Vec::new, |keys| keys.iter().cloned().collect()))
+    }
+
+    /// Returns true if the value of `entry` at the indexed `path` equals `value`.
+    pub fn matches(&self, path: &str, entry: &Entry, value: &Value) -> bool {
+        let indexes = self.indexes.read().unwrap();
+        let Some(index) = indexes.get(path) else {
+            return false;
+        };
+        let values = index.lock().unwrap().values(&entry.value);
+        values.contains(&canonical(value))
+    }
+}
+
+/// Returns a form of `value` that is equal for equal JSON values, so that e.g. `1` and
+/// `1.0` are indexed alike. Object members are already kept sorted by `serde_json`.
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Number(number) =>
+            match number.as_f64() {
+                Some(f) if f.fract() == 0.0 && f.abs() < 9007199254740992.0 => {
+                    format!("{}", f as i64)
+                }
+                _ => number.to_string(),
+            }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Object(members) => {
+            let members: Vec<String> = members
+                .iter()
+                .map(|(name, value)| format!("{}:{}", Value::from(name.as_str()), canonical(value)))
+                .collect();
+            format!("{{{}}}", members.join(","))
+        }
+        _ => value.to_string(),
+    }
+}
@@ -0,0 +1,3 @@
+//! Building blocks of the micro-kv server that are usable on their own.
+
+pub mod storage;
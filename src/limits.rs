@@ -0,0 +1,179 @@
+use crate::config::Config;
+use crate::engine::{ Data, WriteError };
+use crate::eviction::{ Evictable, EvictionPolicy };
+use serde::Serialize;
+use serde_json::Value;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::{ Arc, RwLock, Weak };
+use std::time::Instant;
+
+/// Limits on what clients may store, taken from the configuration. Zero means no limit.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Limits {
+    /// Longest key, in bytes.
+    pub max_key_length: usize,
+    /// Largest value, in bytes of its compact JSON encoding or of its raw bytes.
+    pub max_value_size: usize,
+    /// Most entries in all namespaces together.
+    pub max_entries: usize,
+    /// Most memory, in approximate bytes, taken up by all entries together.
+    pub max_memory: usize,
+    /// Which keys to evict when a write would exceed `max_entries` or `max_memory`.
+    pub eviction_policy: EvictionPolicy,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Self {
+        Limits {
+            max_key_length: config.max_key_length,
+            max_value_size: config.max_value_size,
+            max_entries: config.max_entries,
+            max_memory: config.max_memory,
+            eviction_policy: config.eviction_policy,
+        }
+    }
+
+    /// Checks that `key` is not too long.
+    pub fn check_key(&self, key: &str) -> Result<(), WriteError> {
+        if self.max_key_length > 0 && key.len() > self.max_key_length {
+            return Err(WriteError::KeyTooLong(self.max_key_length));
+        }
+        Ok(())
+    }
+
+    /// Checks that `value` is not too large.
+    pub fn check_value(&self, value: &Data) -> Result<(), WriteError> {
+        match value {
+            Data::Json(value) => self.check_json(value),
+            Data::Bytes { bytes, .. } => self.check_size(bytes.len()),
+        }
+    }
+
+    /// Checks that the JSON document `value` is not too large.
+    pub fn check_json(&self, value: &Value) -> Result<(), WriteError> {
+        if self.max_value_size == 0 {
+            return Ok(());
+        }
+        let mut counter = Counter(0);
+        serde_json::to_writer(&mut counter, value).map_err(io::Error::from)?;
+        self.check_size(counter.0)
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), WriteError> {
+        if self.max_value_size > 0 && size > self.max_value_size {
+            return Err(WriteError::ValueTooLarge(self.max_value_size));
+        }
+        Ok(())
+    }
+}
+
+/// Counts the bytes written to it, to measure an encoding without keeping it.
+struct Counter(usize);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The entry and memory limits shared by every namespace, their combined usage, and the
+/// engines keys can be evicted from to stay within them.
+pub struct Capacity {
+    pub limits: Limits,
+    /// Approximate bytes taken up by all entries.
+    used: AtomicUsize,
+    entries: AtomicUsize,
+    engines: RwLock<Vec<Weak<dyn Evictable>>>,
+}
+
+impl Capacity {
+    pub fn new(limits: Limits) -> Self {
+        Capacity {
+            limits,
+            used: AtomicUsize::new(0),
+            entries: AtomicUsize::new(0),
+            engines: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Lets writes to any engine sharing this capacity evict the keys of `engine`.
+    pub fn register(&self, engine: Weak<dyn Evictable>) {
+        self.engines.write().unwrap().push(engine);
+    }
+
+    /// Evicts keys by the eviction policy, from every registered engine, until `bytes` more
+    /// bytes in `entries` more entries fit within the limits. The keys `writer` is about to
+    /// write, `keep`, are never evicted, so a write cannot remove the entry it derives from.
+    ///
+    /// Like the limits themselves this is approximate: concurrent writes may each see room
+    /// for themselves, and usage is only brought back under the limits by the next write.
+    pub fn reclaim(
+        &self,
+        bytes: usize,
+        entries: usize,
+        writer: &dyn Evictable,
+        keep: &[&str]
+    ) -> Result<(), WriteError> {
+        if !self.is_exceeded(bytes, entries) {
+            return Ok(());
+        }
+        let policy = self.limits.eviction_policy;
+        if policy == EvictionPolicy::NoEviction || self.is_too_large(bytes, entries) {
+            return Err(WriteError::OutOfMemory);
+        }
+        let engines: Vec<Arc<dyn Evictable>> = self.engines
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        while self.is_exceeded(bytes, entries) {
+            let now = Instant::now();
+            // Ranks are comparable across engines, so the lowest of their samples is evicted.
+            let (_, key, engine) = engines
+                .iter()
+                .filter_map(|engine| {
+                    let keep = if ptr::addr_eq(Arc::as_ptr(engine), writer) { keep } else { &[] };
+                    engine.sample(policy, now, keep).map(|(rank, key)| (rank, key, engine))
+                })
+                .min_by_key(|(rank, _, _)| *rank)
+                .ok_or(WriteError::OutOfMemory)?;
+            engine.evict(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if storing `bytes` more bytes in `entries` more entries would exceed
+    /// the limits.
+    pub fn is_exceeded(&self, bytes: usize, entries: usize) -> bool {
+        let Limits { max_memory, max_entries, .. } = self.limits;
+        let used = self.used.load(Ordering::Relaxed);
+        let count = self.entries.load(Ordering::Relaxed);
+        (max_memory > 0 && used.saturating_add(bytes) > max_memory) ||
+            (max_entries > 0 && count.saturating_add(entries) > max_entries)
+    }
+
+    /// Returns true if `bytes` more bytes in `entries` more entries exceed the limits even
+    /// with nothing else stored.
+    pub fn is_too_large(&self, bytes: usize, entries: usize) -> bool {
+        let Limits { max_memory, max_entries, .. } = self.limits;
+        (max_memory > 0 && bytes > max_memory) || (max_entries > 0 && entries > max_entries)
+    }
+
+    pub fn add(&self, bytes: usize, entries: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        self.entries.fetch_add(entries, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, bytes: usize, entries: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.entries.fetch_sub(entries, Ordering::Relaxed);
+    }
+}
@@ -3,178 +3,1144 @@ extern crate rocket;
 extern crate serde;
 extern crate serde_json;
 
-use async_std::task;
-use rocket::response::status;
+mod aof;
+mod auth;
+mod config;
+mod engine;
+mod error;
+mod eviction;
+mod expiry;
+mod index;
+mod limits;
+mod namespace;
+mod patch;
+mod precondition;
+mod query;
+mod snapshot;
+mod ttl;
+
+use auth::{ Action, Caller, Credentials };
+use engine::{ Data, Entry, Storage, WriteError };
+use patch::Operation;
+use glob::Pattern;
+use limits::Limits;
+use error::Error;
+use namespace::{ Namespace, Namespaces };
+use precondition::Precondition;
+use query::{ Path, Predicate };
+use rocket::http::{ ContentType, Header };
 use rocket::serde::json::Json;
-use rocket::State;
-use serde_json::{ json, Value };
-use serde::Serialize;
+use rocket::{ Build, Rocket, State };
+use serde_json::{ json, Number, Value };
+use serde::{ Deserialize, Serialize };
 use slog::{ o, Drain, Logger };
 use std::collections::HashMap;
 use std::io;
+use std::str::FromStr;
 use std::sync::{ Arc, Mutex };
-use std::time::{ Duration, Instant };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use ttl::TtlOptions;
 
 #[derive(Serialize)]
 struct EntryWithMetadata {
-    value: Value,
+    value: Data,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// How `value` is encoded, if it is not as stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
     ttl: Option<f64>,
+    version: u64,
+}
+
+impl EntryWithMetadata {
+    fn new(entry: &Entry, now: Instant) -> Self {
+        let content_type = match &entry.value {
+            Data::Json(_) => None,
+            Data::Bytes { content_type, .. } => Some(content_type.clone()),
+        };
+        EntryWithMetadata {
+            value: entry.value.clone(),
+            content_type,
+            encoding: entry.value.encoding(),
+            ttl: entry.expiry.map(|expiry_time|
+                expiry_time.saturating_duration_since(now).as_secs_f64()
+            ),
+            version: entry.version,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PageEntry {
+    key: String,
+    #[serde(flatten)]
+    entry: EntryWithMetadata,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ListResponse {
+    Entries(HashMap<String, EntryWithMetadata>),
+    Keys(Vec<String>),
+    Page {
+        cursor: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        keys: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        entries: Option<Vec<PageEntry>>,
+    },
+}
+
+/// A stored value, sent back with the media type it was stored with.
+#[derive(Responder)]
+enum ValueResponse {
+    Json(Json<Arc<Value>>),
+    Bytes(Vec<u8>, ContentType),
+}
+
+/// A response carrying the version of the entry it concerns as its `ETag`.
+#[derive(Responder)]
+struct Versioned<R> {
+    inner: R,
+    etag: Header<'static>,
+}
+
+impl<R> Versioned<R> {
+    fn new(inner: R, version: u64) -> Self {
+        Versioned { inner, etag: Header::new("ETag", precondition::etag(version)) }
+    }
 }
 
+/// The remaining time to live of a key, `null` if the key does not expire, and whether
+/// reading the key renews it.
 #[derive(Serialize)]
 struct TtlResponse {
     ttl: Option<f64>,
-    status: &'static str,
+    ttl_ms: Option<u64>,
+    sliding: bool,
+    status: String,
 }
 
-// Define type aliases for readability.
-type ExpiryTime = Option<Instant>;
-type Db = Arc<Mutex<HashMap<String, (String, ExpiryTime)>>>;
-
-/// Periodically cleans up expired keys in the database.
-async fn cleanup_expired_keys(db: Db) {
-    loop {
-        let sleep_duration = {
-            let db = db.lock().unwrap();
-            db.iter()
-                .filter_map(|(_, (_, expiry))| *expiry)
-                .min()
-                .map(|next_expiry| {
-                    let now = Instant::now();
-                    if next_expiry > now {
-                        next_expiry.duration_since(now)
-                    } else {
-                        Duration::from_secs(0)
-                    }
-                })
-                .unwrap_or(Duration::from_secs(1))
-        };
+impl TtlResponse {
+    fn new(expiry: ExpiryTime, sliding: Option<Duration>, status: String) -> Self {
+        let remaining = expiry.map(|expiry_time| expiry_time.saturating_duration_since(Instant::now()));
+        TtlResponse {
+            ttl: remaining.map(|remaining| remaining.as_secs_f64()),
+            ttl_ms: remaining.map(|remaining| remaining.as_millis() as u64),
+            sliding: sliding.is_some(),
+            status,
+        }
+    }
+}
+
+/// An entry to store with `/batch/set`.
+#[derive(Deserialize)]
+struct BatchItem {
+    key: String,
+    value: Value,
+    ttl: Option<u64>,
+    ttl_ms: Option<u64>,
+}
 
-        task::sleep(sleep_duration).await;
+impl BatchItem {
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl.map(Duration::from_secs).or(self.ttl_ms.map(Duration::from_millis))
+    }
 
-        let now = Instant::now();
-        let mut db = db.lock().unwrap();
-        db.retain(|_, (_, expiry)| expiry.map_or(true, |e| e > now));
+    fn validate(&self, limits: &Limits, log: &Logger) -> Result<(), Error> {
+        if self.key.is_empty() {
+            return Err(Error::BadRequest("Invalid key: empty".to_string()));
+        }
+        if self.ttl.is_some() && self.ttl_ms.is_some() {
+            return Err(Error::BadRequest("Only one of ttl and ttl_ms may be given".to_string()));
+        }
+        if self.ttl().is_some_and(|ttl| ttl::deadline(Instant::now(), ttl).is_none()) {
+            let (name, value) = match self.ttl {
+                Some(ttl) => ("ttl", ttl),
+                None => ("ttl_ms", self.ttl_ms.unwrap_or_default()),
+            };
+            return Err(Error::BadRequest(format!("Invalid {}: {}", name, value)));
+        }
+        limits
+            .check_key(&self.key)
+            .and_then(|()| limits.check_json(&self.value))
+            .map_err(|e| write_error(e, &self.key, log))
     }
 }
 
-/// Retrieves all active (non-expired) entries from the database.
-#[get("/")]
-fn get_all(db: &State<Db>) -> Json<HashMap<String, EntryWithMetadata>> {
-    let db = db.lock().unwrap();
-    let mut response = HashMap::new();
-
-    for (key, (serialized, expiry)) in db.iter() {
-        match serde_json::from_str(serialized) {
-            Ok(deserialized) => {
-                let expiry_metadata = expiry.map(|expiry_time|
-                    expiry_time.saturating_duration_since(Instant::now()).as_secs_f64()
-                );
-                let entry_with_metadata = EntryWithMetadata {
-                    value: deserialized,
-                    ttl: expiry_metadata,
-                };
-                response.insert(key.clone(), entry_with_metadata);
-            }
-            Err(e) => eprintln!("Error deserializing JSON: {:?}", e),
+/// Outcome of a batch operation for a single key.
+#[derive(Serialize)]
+struct BatchResult {
+    key: String,
+    status: u16,
+    #[serde(flatten)]
+    entry: Option<EntryWithMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchResult {
+    fn ok(key: &str, entry: Option<EntryWithMetadata>) -> Self {
+        BatchResult { key: key.to_string(), status: 200, entry, version: None, error: None }
+    }
+
+    fn err(key: &str, error: Error) -> Self {
+        BatchResult {
+            key: key.to_string(),
+            status: error.status().code,
+            entry: None,
+            version: None,
+            error: Some(error.message()),
         }
     }
-    Json(response)
 }
 
-/// Retrieves a specific entry by key from the database, if it is not expired.
-#[get("/<key>")]
-fn get(key: &str, db: &State<Db>, log: &State<Logger>) -> Json<Value> {
-    let db = db.lock().unwrap();
-    match db.get(key) {
-        Some((serialized, _)) =>
-            match serde_json::from_str(serialized) {
-                Ok(deserialized) => Json(deserialized),
-                Err(e) => {
-                    let status = "Error deserializing JSON".to_string();
-                    slog::error!(log, "{}: {:?}", status, e);
-                    Json(json!({ "status": status }))
+// Define type aliases for readability.
+type ExpiryTime = Option<Instant>;
+type Db = Arc<dyn Storage>;
+
+/// Number of entries per page when listing with a `cursor` but no `limit`.
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// Largest page size a client may request.
+const MAX_PAGE_SIZE: usize = 10_000;
+
+/// Converts an expiry deadline to milliseconds since the Unix epoch.
+fn to_unix_millis(expiry: Instant) -> u64 {
+    let remaining = expiry.saturating_duration_since(Instant::now());
+    let Some(deadline) = SystemTime::now().checked_add(remaining) else {
+        return u64::MAX;
+    };
+    deadline
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| u64::try_from(since.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Converts milliseconds since the Unix epoch back to an expiry deadline,
+/// returning `None` if the deadline has already passed.
+fn from_unix_millis(millis: u64) -> Option<Instant> {
+    let deadline = UNIX_EPOCH + Duration::from_millis(millis);
+    let remaining = deadline.duration_since(SystemTime::now()).ok()?;
+    Some(Instant::now() + remaining)
+}
+
+/// Retrieves all active (non-expired) entries from the database.
+///
+/// Entries can be filtered by key `prefix`, glob `pattern` and a `filter` predicate on their
+/// JSON values such as `status == "active"`, and listed as keys only. Only the keys the
+/// caller may list are returned, and only those it may also get when values are included.
+/// Given a `cursor` or `limit`, entries are returned a page at a time in key order,
+/// along with the cursor for the next page, which is `null` once all entries were visited.
+#[get("/?<cursor>&<limit>&<prefix>&<pattern>&<filter>&<keys_only>")]
+#[allow(clippy::too_many_arguments)]
+fn get_all(
+    cursor: Option<&str>,
+    limit: Option<&str>,
+    prefix: Option<&str>,
+    pattern: Option<&str>,
+    filter: Option<&str>,
+    keys_only: Option<bool>,
+    caller: Caller,
+    db: Namespace
+) -> Result<Json<ListResponse>, Error> {
+    let limit = match parse_query::<usize>("limit", limit)? {
+        Some(0) => {
+            return Err(Error::BadRequest("Invalid limit: 0".to_string()));
+        }
+        limit => limit,
+    };
+    let keys_only = keys_only.unwrap_or(false);
+    let pattern = pattern
+        .map(|p| Pattern::new(p).map_err(|_| Error::BadRequest(format!("Invalid pattern: {}", p))))
+        .transpose()?;
+    let predicate = filter
+        .map(|f| Predicate::parse(f).ok_or_else(|| Error::BadRequest(format!("Invalid filter: {}", f))))
+        .transpose()?;
+    let filter = |key: &str, entry: &Entry| {
+        caller.allows(Action::List, &db.name, key) &&
+            (keys_only || caller.allows(Action::Get, &db.name, key)) &&
+            prefix.is_none_or(|prefix| key.starts_with(prefix)) &&
+            pattern.as_ref().is_none_or(|pattern| pattern.matches(key)) &&
+            predicate.as_ref().is_none_or(|predicate| match &entry.value {
+                Data::Json(value) => predicate.matches(value),
+                Data::Bytes { .. } => false,
+            })
+    };
+    let now = Instant::now();
+
+    if cursor.is_none() && limit.is_none() {
+        let mut keys = Vec::new();
+        let mut entries = HashMap::new();
+        db.scan(
+            &mut (|key, entry| {
+                if !filter(key, entry) {
+                    return;
                 }
-            }
-        None => {
-            let status = format!("Key not found: {}", key);
-            Json(json!({ "status": status }))
+                if keys_only {
+                    keys.push(key.to_string());
+                } else {
+                    entries.insert(key.to_string(), EntryWithMetadata::new(entry, now));
+                }
+            })
+        );
+        if keys_only {
+            keys.sort_unstable();
+            return Ok(Json(ListResponse::Keys(keys)));
         }
+        return Ok(Json(ListResponse::Entries(entries)));
     }
+
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let after = cursor.filter(|cursor| !cursor.is_empty());
+    let page = db.scan_page(after, limit, &filter);
+    let next_cursor = match page.last() {
+        Some((key, _)) if page.len() == limit => Some(key.clone()),
+        _ => None,
+    };
+    let (keys, entries) = if keys_only {
+        (Some(page.into_iter().map(|(key, _)| key).collect()), None)
+    } else {
+        let entries = page
+            .into_iter()
+            .map(|(key, entry)| PageEntry { key, entry: EntryWithMetadata::new(&entry, now) })
+            .collect();
+        (None, Some(entries))
+    };
+    Ok(
+        Json(ListResponse::Page {
+            cursor: next_cursor,
+            keys,
+            entries,
+        })
+    )
 }
 
-/// Retrieves the ttl for a specific entry by key from the database.
-#[get("/ttl/<key>")]
-fn get_ttl(key: &str, db: &State<Db>) -> Result<Json<TtlResponse>, status::NotFound<String>> {
-    let db = db.lock().unwrap();
-    match db.get(key) {
-        Some((_, expiry)) => {
-            let ttl = expiry.map(|expiry_time|
-                expiry_time.saturating_duration_since(Instant::now()).as_secs_f64()
+/// Retrieves a specific entry by key from the database, if it is not expired,
+/// with its version as the `ETag`. Reading a key with a sliding TTL renews its expiry.
+///
+/// Given a `path`, only the part of a JSON value at that JSON Pointer or JSONPath is returned.
+/// A path that can select several values, such as `$.items[*].id`, returns them as an array.
+#[get("/<key>?<path>")]
+fn get(
+    key: &str,
+    path: Option<&str>,
+    caller: Caller,
+    db: Namespace
+) -> Result<Versioned<ValueResponse>, Error> {
+    caller.require(Action::Get, &db.name, key)?;
+    let path = path
+        .map(|p| {
+            let parsed = Path::parse(p).ok_or_else(|| Error::BadRequest(format!("Invalid path: {}", p)))?;
+            Ok((p, parsed))
+        })
+        .transpose()?;
+    let entry = db.get(key).ok_or_else(|| Error::KeyNotFound(key.to_string()))?;
+    let response = match (entry.value, path) {
+        (Data::Json(value), None) => ValueResponse::Json(Json(value)),
+        (Data::Json(value), Some((p, path))) => {
+            let mut selected = path.select(&value);
+            let value = if path.is_singular() {
+                selected.pop().ok_or_else(|| Error::PathNotFound(p.to_string()))?.clone()
+            } else {
+                Value::Array(selected.into_iter().cloned().collect())
+            };
+            ValueResponse::Json(Json(Arc::new(value)))
+        }
+        (Data::Bytes { content_type, bytes }, None) => {
+            let content_type = ContentType::parse_flexible(&content_type).unwrap_or(
+                ContentType::Binary
             );
-            Ok(
-                Json(TtlResponse {
-                    ttl,
-                    status: "success",
-                })
-            )
+            ValueResponse::Bytes(bytes.to_vec(), content_type)
+        }
+        (Data::Bytes { .. }, Some(_)) => {
+            return Err(Error::Conflict(format!("Value is not JSON: {}", key)));
         }
-        None => Err(status::NotFound(format!("Key not found: {}", key))),
+    };
+    Ok(Versioned::new(response, entry.version))
+}
+
+/// Retrieves the ttl for a specific entry by key from the database.
+///
+/// A key without a TTL has a `null` ttl, while a missing or expired key is not found.
+/// Checking the TTL does not renew a sliding expiry.
+#[get("/ttl/<key>")]
+fn get_ttl(key: &str, caller: Caller, db: Namespace) -> Result<Json<TtlResponse>, Error> {
+    caller.require(Action::Get, &db.name, key)?;
+    let entry = db.peek(key).ok_or_else(|| Error::KeyNotFound(key.to_string()))?;
+    Ok(Json(TtlResponse::new(entry.expiry, entry.sliding, "success".to_string())))
+}
+
+/// Sets the TTL of an existing key to `ttl` seconds or `ttl_ms` milliseconds from now,
+/// keeping its value. With `sliding`, the TTL is renewed whenever the key is read.
+#[post("/expire/<key>")]
+fn expire(
+    key: &str,
+    options: Result<TtlOptions, Error>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<TtlResponse>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    let options = options?;
+    if options.ttl.is_none() {
+        return Err(Error::BadRequest("Missing ttl".to_string()));
     }
+    set_expiry(key, options.apply(None, Instant::now()), &db, log)
+}
+
+/// Sets an existing key to expire at the Unix timestamp `at`, in seconds, or `at_ms`,
+/// in milliseconds. A timestamp in the past expires the key immediately.
+#[post("/expireat/<key>?<at>&<at_ms>")]
+fn expire_at(
+    key: &str,
+    at: Option<&str>,
+    at_ms: Option<&str>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<TtlResponse>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    let at = parse_query::<u64>("at", at)?.map(|at| at.saturating_mul(1000));
+    let at_ms = parse_query::<u64>("at_ms", at_ms)?;
+    let millis = match (at, at_ms) {
+        (Some(millis), None) | (None, Some(millis)) => millis,
+        (None, None) => {
+            return Err(Error::BadRequest("Missing at".to_string()));
+        }
+        (Some(_), Some(_)) => {
+            return Err(Error::BadRequest("Only one of at and at_ms may be given".to_string()));
+        }
+    };
+    let expiry = from_unix_millis(millis).unwrap_or_else(Instant::now);
+    set_expiry(key, (Some(expiry), None), &db, log)
+}
+
+/// Removes the TTL of an existing key, so it no longer expires.
+#[post("/persist/<key>")]
+fn persist(
+    key: &str,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<TtlResponse>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    set_expiry(key, (None, None), &db, log)
+}
+
+fn set_expiry(
+    key: &str,
+    (expiry, sliding): (ExpiryTime, Option<Duration>),
+    db: &Db,
+    log: &State<Logger>
+) -> Result<Json<TtlResponse>, Error> {
+    db.update(
+        key,
+        &Precondition::default(),
+        &mut (|current| {
+            let entry = current.ok_or(WriteError::KeyNotFound)?;
+            Ok((entry.value.clone(), expiry, sliding))
+        })
+    ).map_err(|e| write_error(e, key, log))?;
+    let status = match expiry {
+        Some(_) => format!("Expiry set: {}", key),
+        None => format!("Expiry removed: {}", key),
+    };
+
+    slog::info!(log, "{}", status);
+    Ok(Json(TtlResponse::new(expiry, sliding, status)))
 }
 
 /// Inserts or updates an entry in the database with an optional TTL.
-#[post("/<key>?<ttl>", format = "json", data = "<entry>")]
+///
+/// With `keepttl` an existing entry keeps its TTL, and with `sliding` the TTL is renewed
+/// whenever the entry is read. With `If-Match` the write only goes ahead if the entry has one of the given versions,
+/// and with `If-None-Match: *` only if the key does not exist yet.
+#[post("/<key>", format = "json", data = "<entry>")]
 fn create(
     key: &str,
-    ttl: Option<u64>,
+    options: Result<TtlOptions, Error>,
+    precondition: Result<Precondition, Error>,
     entry: Json<Value>,
-    db: &State<Db>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Versioned<Json<Value>>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    insert(key, options?, precondition?, Data::Json(Arc::new(entry.into_inner())), &db, log)
+}
+
+/// Inserts or updates a non-JSON entry, such as plain text or binary data, keeping its
+/// content type so it is returned as-is.
+#[post("/<key>", data = "<body>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+fn create_bytes(
+    key: &str,
+    options: Result<TtlOptions, Error>,
+    precondition: Result<Precondition, Error>,
+    content_type: Option<&ContentType>,
+    body: Vec<u8>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Versioned<Json<Value>>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    let content_type = content_type.unwrap_or(&ContentType::Binary).to_string();
+    insert(key, options?, precondition?, Data::Bytes { content_type, bytes: body.into() }, &db, log)
+}
+
+fn insert(
+    key: &str,
+    options: TtlOptions,
+    precondition: Precondition,
+    value: Data,
+    db: &Db,
+    log: &State<Logger>
+) -> Result<Versioned<Json<Value>>, Error> {
+    let now = Instant::now();
+    let version = if options.keep {
+        db.update(
+            key,
+            &precondition,
+            &mut (|current| {
+                let (expiry, sliding) = options.apply(current, now);
+                Ok((value.clone(), expiry, sliding))
+            })
+        ).map(|entry| entry.version)
+    } else {
+        let (expiry, sliding) = options.apply(None, now);
+        db.set(key, value, expiry, sliding, &precondition)
+    };
+    let version = version.map_err(|e| write_error(e, key, log))?;
+    let status = format!("Key inserted: {}", key);
+
+    slog::info!(log, "{}", status);
+    Ok(Versioned::new(Json(json!({ "status": status, "version": version })), version))
+}
+
+/// Removes a specific entry by key from the database.
+///
+/// With `If-Match` the entry is only removed if it has one of the given versions.
+#[delete("/<key>")]
+fn delete(
+    key: &str,
+    precondition: Result<Precondition, Error>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<Value>, Error> {
+    caller.require(Action::Delete, &db.name, key)?;
+    let deleted = db.delete(key, &precondition?).map_err(|e| write_error(e, key, log))?;
+    if !deleted {
+        return Err(Error::KeyNotFound(key.to_string()));
+    }
+    let status = format!("Key deleted: {}", key);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status })))
+}
+
+/// Atomically adds `by`, 1 by default, to the number stored under `key`, creating it at 0
+/// if it does not exist.
+///
+/// The sum stays an integer if both numbers are integers. The entry keeps its TTL unless
+/// a new `ttl` is given.
+#[post("/incr/<key>?<by>")]
+fn incr(
+    key: &str,
+    by: Option<&str>,
+    options: Result<TtlOptions, Error>,
+    precondition: Result<Precondition, Error>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Versioned<Json<EntryWithMetadata>>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    let by = parse_query("by", by)?.unwrap_or_else(|| Number::from(1));
+    increment(key, by, options?, precondition?, &db, log)
+}
+
+/// Atomically subtracts `by`, 1 by default, from the number stored under `key`, creating it
+/// at 0 if it does not exist.
+#[post("/decr/<key>?<by>")]
+fn decr(
+    key: &str,
+    by: Option<&str>,
+    options: Result<TtlOptions, Error>,
+    precondition: Result<Precondition, Error>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Versioned<Json<EntryWithMetadata>>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    let by = parse_query::<Number>("by", by)?.unwrap_or_else(|| Number::from(1));
+    let negated = match by.as_i64() {
+        Some(by) => by.checked_neg().map(Number::from),
+        None => by.as_f64().and_then(|by| Number::from_f64(-by)),
+    };
+    let by = negated.ok_or_else(|| Error::BadRequest(format!("Invalid by: {}", by)))?;
+    increment(key, by, options?, precondition?, &db, log)
+}
+
+fn increment(
+    key: &str,
+    by: Number,
+    options: TtlOptions,
+    precondition: Precondition,
+    db: &Db,
     log: &State<Logger>
-) -> Json<Value> {
-    let mut db = db.lock().unwrap();
-    let expiry = ttl.map(|t| Instant::now() + Duration::from_secs(t));
-    match serde_json::to_string(&*entry) {
-        Ok(serialized) => {
-            db.insert(key.to_string(), (serialized, expiry));
-            let status = format!("Key inserted: {}", key);
+) -> Result<Versioned<Json<EntryWithMetadata>>, Error> {
+    let now = Instant::now();
+    let entry = db
+        .update(
+            key,
+            &precondition,
+            &mut (|current| {
+                let number = match current.map(|entry| &entry.value) {
+                    None => Some(Number::from(0)),
+                    Some(Data::Json(value)) => value.as_number().cloned(),
+                    Some(Data::Bytes { .. }) => None,
+                };
+                let number = number.ok_or_else(|| {
+                    WriteError::InvalidValue(format!("Value is not a number: {}", key))
+                })?;
+                let sum = add(&number, &by).ok_or_else(|| {
+                    WriteError::InvalidValue(format!("Increment would overflow: {}", key))
+                })?;
+                let (expiry, sliding) = TtlOptions { keep: true, ..options }.apply(current, now);
+                Ok((Data::Json(Arc::new(Value::Number(sum))), expiry, sliding))
+            })
+        )
+        .map_err(|e| write_error(e, key, log))?;
+    let status = format!("Key incremented: {}", key);
+
+    slog::info!(log, "{}", status);
+    Ok(Versioned::new(Json(EntryWithMetadata::new(&entry, now)), entry.version))
+}
+
+/// Updates part of a JSON entry with a JSON Patch document (RFC 6902).
+///
+/// The operations are applied atomically: if any of them fails, the entry is left unchanged.
+/// The entry keeps its TTL unless a new `ttl` is given.
+#[patch("/<key>", format = "application/json-patch+json", data = "<operations>")]
+fn json_patch(
+    key: &str,
+    options: Result<TtlOptions, Error>,
+    precondition: Result<Precondition, Error>,
+    operations: Json<Vec<Operation>>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Versioned<Json<Value>>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    patch(key, options?, precondition?, &mut (|doc| patch::apply(doc, &operations)), &db, log)
+}
+
+/// Updates part of a JSON entry with a JSON Merge Patch document (RFC 7396).
+///
+/// The entry keeps its TTL unless a new `ttl` is given.
+#[patch("/<key>", format = "application/merge-patch+json", data = "<merge_patch>")]
+fn merge_patch(
+    key: &str,
+    options: Result<TtlOptions, Error>,
+    precondition: Result<Precondition, Error>,
+    merge_patch: Json<Value>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Versioned<Json<Value>>, Error> {
+    caller.require(Action::Set, &db.name, key)?;
+    let mut merge = |doc: &mut Value| {
+        patch::merge(doc, &merge_patch);
+        Ok(())
+    };
+    patch(key, options?, precondition?, &mut merge, &db, log)
+}
+
+/// Rejects patches in any format other than JSON Patch and JSON Merge Patch.
+#[patch("/<key>", rank = 2)]
+fn unsupported_patch(key: &str, caller: Caller, db: Namespace) -> Error {
+    if let Err(e) = caller.require(Action::Set, &db.name, key) {
+        return e;
+    }
+    Error::UnsupportedMediaType(
+        "Patches must be application/json-patch+json or application/merge-patch+json".to_string()
+    )
+}
+
+fn patch(
+    key: &str,
+    options: TtlOptions,
+    precondition: Precondition,
+    apply: &mut dyn FnMut(&mut Value) -> Result<(), String>,
+    db: &Db,
+    log: &State<Logger>
+) -> Result<Versioned<Json<Value>>, Error> {
+    let now = Instant::now();
+    let entry = db
+        .update(
+            key,
+            &precondition,
+            &mut (|current| {
+                let mut doc = match current.map(|entry| &entry.value) {
+                    None => {
+                        return Err(WriteError::KeyNotFound);
+                    }
+                    Some(Data::Json(value)) => Value::clone(value),
+                    Some(Data::Bytes { .. }) => {
+                        return Err(WriteError::InvalidValue(format!("Value is not JSON: {}", key)));
+                    }
+                };
+                apply(&mut doc).map_err(WriteError::InvalidValue)?;
+                let (expiry, sliding) = TtlOptions { keep: true, ..options }.apply(current, now);
+                Ok((Data::Json(Arc::new(doc)), expiry, sliding))
+            })
+        )
+        .map_err(|e| write_error(e, key, log))?;
+    let status = format!("Key patched: {}", key);
+
+    slog::info!(log, "{}", status);
+    Ok(Versioned::new(Json(json!({ "status": status, "version": entry.version })), entry.version))
+}
 
-            slog::info!(log, "{}", status);
-            Json(json!({ "status": status }))
+/// Retrieves several entries at once, as they were at a single instant.
+///
+/// With `atomic`, the request fails unless every key exists.
+#[post("/batch/get?<atomic>", format = "json", data = "<keys>")]
+fn batch_get(
+    atomic: Option<bool>,
+    keys: Json<Vec<String>>,
+    caller: Caller,
+    db: Namespace
+) -> Result<Json<Value>, Error> {
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let atomic = atomic.unwrap_or(false);
+    let permitted = permit(&keys, Action::Get, &caller, &db, atomic)?;
+    let mut found = db.get_many(&allowed(&keys, &permitted)).into_iter();
+    let entries: Vec<Result<Option<Entry>, Error>> = permitted
+        .into_iter()
+        .map(|permitted| permitted.map(|()| found.next().flatten()))
+        .collect();
+    if atomic {
+        if let Some(i) = entries.iter().position(|entry| matches!(entry, Ok(None))) {
+            return Err(Error::KeyNotFound(keys[i].to_string()));
         }
-        Err(e) => {
-            eprintln!("Error serializing JSON: {:?}", e);
-            Json(json!({"status": "Error Creating Item"}))
+    }
+
+    let now = Instant::now();
+    let results: Vec<BatchResult> = keys
+        .iter()
+        .zip(entries)
+        .map(|(key, entry)| match entry {
+            Ok(Some(entry)) => BatchResult::ok(key, Some(EntryWithMetadata::new(&entry, now))),
+            Ok(None) => BatchResult::err(key, Error::KeyNotFound(key.to_string())),
+            Err(e) => BatchResult::err(key, e),
+        })
+        .collect();
+    Ok(Json(json!({ "results": results })))
+}
+
+/// Inserts or updates several entries at once, each with an optional TTL.
+///
+/// Invalid entries, such as ones with an empty or too long key, are rejected individually,
+/// or fail the whole request with `atomic`.
+#[post("/batch/set?<atomic>", format = "json", data = "<items>")]
+fn batch_set(
+    atomic: Option<bool>,
+    items: Json<Vec<BatchItem>>,
+    caller: Caller,
+    db: Namespace,
+    limits: &State<Limits>,
+    log: &State<Logger>
+) -> Result<Json<Value>, Error> {
+    let validated: Vec<Result<(), Error>> = items
+        .iter()
+        .map(|item| {
+            caller.require(Action::Set, &db.name, &item.key).and_then(|()| item.validate(limits, log))
+        })
+        .collect();
+    if atomic.unwrap_or(false) {
+        validated.iter().cloned().collect::<Result<(), Error>>()?;
+    }
+
+    let now = Instant::now();
+    let mut results: Vec<BatchResult> = items
+        .iter()
+        .zip(&validated)
+        .map(|(item, validated)| {
+            match validated {
+                Ok(()) => BatchResult::ok(&item.key, None),
+                Err(e) => BatchResult::err(&item.key, e.clone()),
+            }
+        })
+        .collect();
+    let entries: Vec<(String, Data, ExpiryTime)> = items
+        .into_inner()
+        .into_iter()
+        .zip(&validated)
+        .filter(|(_, validated)| validated.is_ok())
+        .map(|(item, _)| {
+            let expiry = item.ttl().and_then(|ttl| ttl::deadline(now, ttl));
+            (item.key, Data::Json(Arc::new(item.value)), expiry)
+        })
+        .collect();
+    let count = entries.len();
+    let mut versions = db
+        .set_many(entries)
+        .map_err(|e| write_error(e, "", log))?
+        .into_iter();
+    for result in results.iter_mut().filter(|result| result.error.is_none()) {
+        result.version = versions.next();
+    }
+    let status = format!("Keys inserted: {}", count);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status, "results": results })))
+}
+
+/// Removes several entries at once. A key given more than once is only deleted by its first
+/// occurrence, and reported not found after that.
+///
+/// With `atomic`, nothing is removed unless every key exists.
+#[post("/batch/delete?<atomic>", format = "json", data = "<keys>")]
+fn batch_delete(
+    atomic: Option<bool>,
+    keys: Json<Vec<String>>,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<Value>, Error> {
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let atomic = atomic.unwrap_or(false);
+    let permitted = permit(&keys, Action::Delete, &caller, &db, atomic)?;
+    let existed = db
+        .delete_many(&allowed(&keys, &permitted), atomic)
+        .map_err(|e| write_error(e, "", log))?;
+    let deleted = existed.iter().filter(|existed| **existed).count();
+    if atomic {
+        if let Some(i) = existed.iter().position(|existed| !existed) {
+            return Err(Error::KeyNotFound(keys[i].to_string()));
         }
     }
+
+    let mut existed = existed.into_iter();
+    let results: Vec<BatchResult> = keys
+        .iter()
+        .zip(permitted)
+        .map(|(key, permitted)| match permitted.map(|()| existed.next().unwrap_or(false)) {
+            Ok(true) => BatchResult::ok(key, None),
+            Ok(false) => BatchResult::err(key, Error::KeyNotFound(key.to_string())),
+            Err(e) => BatchResult::err(key, e),
+        })
+        .collect();
+    let status = format!("Keys deleted: {}", deleted);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status, "results": results })))
+}
+
+/// Checks that the caller may perform `action` on each of `keys`, failing the request if it
+/// may not perform it on one of them and the batch is `atomic`.
+fn permit(
+    keys: &[&str],
+    action: Action,
+    caller: &Caller,
+    db: &Namespace,
+    atomic: bool
+) -> Result<Vec<Result<(), Error>>, Error> {
+    let permitted: Vec<Result<(), Error>> = keys
+        .iter()
+        .map(|key| caller.require(action, &db.name, key))
+        .collect();
+    if atomic {
+        permitted.iter().cloned().collect::<Result<(), Error>>()?;
+    }
+    Ok(permitted)
 }
 
-/// Removes a specific entry by key from the database.
-#[delete("/<key>")]
-fn delete(key: &str, db: &State<Db>, log: &State<Logger>) -> Json<Value> {
-    let mut db = db.lock().unwrap();
-    if db.remove(key).is_some() {
-        let status = format!("Key deleted: {}", key);
+/// Returns the keys the caller is permitted to access.
+fn allowed<'a>(keys: &[&'a str], permitted: &[Result<(), Error>]) -> Vec<&'a str> {
+    keys.iter()
+        .zip(permitted)
+        .filter(|(_, permitted)| permitted.is_ok())
+        .map(|(key, _)| *key)
+        .collect()
+}
 
-        slog::info!(log, "{}", status);
-        Json(json!({ "status": status }))
-    } else {
-        Json(json!({"status": "Key Not Found"}))
+/// Looks up the entries whose value at an indexed `path` equals `value`, without scanning
+/// the database. `value` is JSON, or a string if it does not parse as JSON.
+#[get("/index/lookup?<path>&<value>&<keys_only>")]
+fn lookup(
+    path: &str,
+    value: &str,
+    keys_only: Option<bool>,
+    caller: Caller,
+    db: Namespace
+) -> Result<Json<ListResponse>, Error> {
+    let keys_only = keys_only.unwrap_or(false);
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+    let entries = db.lookup(path, &value).ok_or_else(|| Error::IndexNotFound(path.to_string()))?;
+    let entries = entries.into_iter().filter(|(key, _)| {
+        caller.allows(Action::List, &db.name, key) &&
+            (keys_only || caller.allows(Action::Get, &db.name, key))
+    });
+    if keys_only {
+        return Ok(Json(ListResponse::Keys(entries.map(|(key, _)| key).collect())));
     }
+    let now = Instant::now();
+    let entries = entries
+        .map(|(key, entry)| (key, EntryWithMetadata::new(&entry, now)))
+        .collect();
+    Ok(Json(ListResponse::Entries(entries)))
+}
+
+/// Lists the paths of all indexes.
+#[get("/admin/indexes")]
+fn list_indexes(caller: Caller, db: Namespace) -> Result<Json<Value>, Error> {
+    caller.require(Action::List, &db.name, "")?;
+    Ok(Json(json!({ "indexes": db.indexes() })))
+}
+
+/// Creates an index on the values at `path` within JSON values, such as `$.email`,
+/// indexing the existing entries before returning.
+#[post("/admin/indexes?<path>")]
+fn create_index(
+    path: &str,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<Value>, Error> {
+    caller.require(Action::Admin, &db.name, "")?;
+    let parsed = Path::parse(path).ok_or_else(|| Error::BadRequest(format!("Invalid path: {}", path)))?;
+    if !db.create_index(path, parsed).map_err(|e| storage_error(e, log))? {
+        return Err(Error::Conflict(format!("Index already exists: {}", path)));
+    }
+    let status = format!("Index created: {}", path);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status })))
+}
+
+/// Drops the index on `path`.
+#[delete("/admin/indexes?<path>")]
+fn drop_index(
+    path: &str,
+    caller: Caller,
+    db: Namespace,
+    log: &State<Logger>
+) -> Result<Json<Value>, Error> {
+    caller.require(Action::Admin, &db.name, "")?;
+    if !db.drop_index(path).map_err(|e| storage_error(e, log))? {
+        return Err(Error::IndexNotFound(path.to_string()));
+    }
+    let status = format!("Index dropped: {}", path);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status })))
+}
+
+/// Lists the names of the namespaces the caller has access to.
+#[get("/admin/namespaces")]
+fn list_namespaces(caller: Caller, namespaces: &State<Namespaces>) -> Json<Value> {
+    let names: Vec<String> = namespaces
+        .names()
+        .into_iter()
+        .filter(|name| caller.sees(name))
+        .collect();
+    Json(json!({ "namespaces": names }))
+}
+
+/// Creates the namespace `name`, whose keys are served under `/ns/<name>`.
+#[post("/admin/namespaces/<name>")]
+fn create_namespace(
+    name: &str,
+    caller: Caller,
+    namespaces: &State<Namespaces>,
+    log: &State<Logger>
+) -> Result<Json<Value>, Error> {
+    caller.require(Action::Admin, name, "")?;
+    if !namespace::is_valid(name) {
+        return Err(Error::BadRequest(format!("Invalid namespace name: {}", name)));
+    }
+    let created = namespaces.create(name).map_err(|e| storage_error(e, log))?;
+    if !created {
+        return Err(Error::Conflict(format!("Namespace already exists: {}", name)));
+    }
+    let status = format!("Namespace created: {}", name);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status })))
+}
+
+/// Returns the number of keys in the namespace, how many of them expire, how many indexes
+/// it has and roughly how much memory its entries take up.
+#[get("/admin/stats")]
+fn stats(caller: Caller, db: Namespace) -> Result<Json<Value>, Error> {
+    caller.require(Action::List, &db.name, "")?;
+    let (mut keys, mut expiring, mut sliding) = (0, 0, 0);
+    db.scan(
+        &mut (|_, entry| {
+            keys += 1;
+            expiring += entry.expiry.is_some() as usize;
+            sliding += entry.sliding.is_some() as usize;
+        })
+    );
+    Ok(
+        Json(
+            json!({
+                "namespace": db.name,
+                "keys": keys,
+                "expiring": expiring,
+                "sliding": sliding,
+                "indexes": db.indexes().len(),
+                "memory": db.memory(),
+            })
+        )
+    )
+}
+
+/// Removes every key of the namespace.
+#[post("/admin/flush")]
+fn flush(caller: Caller, db: Namespace, log: &State<Logger>) -> Result<Json<Value>, Error> {
+    caller.require(Action::Admin, &db.name, "")?;
+    let count = db.flush().map_err(|e| storage_error(e, log))?;
+    let status = format!("Namespace flushed: {}", db.name);
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status, "deleted": count })))
+}
+
+/// Returns the limits on keys, values, entries and memory.
+#[get("/admin/config")]
+fn get_config(
+    caller: Caller,
+    db: Namespace,
+    limits: &State<Limits>,
+) -> Result<Json<Limits>, Error> {
+    caller.require(Action::Admin, &db.name, "")?;
+    Ok(Json(**limits))
+}
+
+/// Compacts the storage engine by rewriting its append-only log from the live contents of the database.
+#[post("/admin/compact")]
+fn compact(caller: Caller, db: Namespace, log: &State<Logger>) -> Result<Json<Value>, Error> {
+    caller.require(Action::Admin, &db.name, "")?;
+    db.compact().map_err(|e| {
+        if e.kind() == io::ErrorKind::Unsupported {
+            return Error::Conflict("Append-only log is disabled".to_string());
+        }
+        slog::error!(log, "Error compacting append-only log: {:?}", e);
+        Error::Internal("Error Compacting Append-Only Log".to_string())
+    })?;
+    let status = "Append-only log compacted";
+
+    slog::info!(log, "{}", status);
+    Ok(Json(json!({ "status": status })))
+}
+
+/// Adds two JSON numbers, keeping the sum an integer if both are integers.
+///
+/// Returns `None` if the sum overflows.
+fn add(a: &Number, b: &Number) -> Option<Number> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return a.checked_add(b).map(Number::from);
+    }
+    Number::from_f64(a.as_f64()? + b.as_f64()?)
+}
+
+/// Parses an optional query parameter, rejecting malformed values instead of ignoring them.
+fn parse_query<T: FromStr>(name: &str, value: Option<&str>) -> Result<Option<T>, Error> {
+    value
+        .map(|v| v.parse().map_err(|_| Error::BadRequest(format!("Invalid {}: {}", name, v))))
+        .transpose()
+}
+
+/// Converts a refused or failed write to `key` to an error response.
+fn write_error(e: WriteError, key: &str, log: &Logger) -> Error {
+    match e {
+        WriteError::KeyNotFound => Error::KeyNotFound(key.to_string()),
+        WriteError::KeyExists => Error::Conflict(format!("Key already exists: {}", key)),
+        WriteError::PreconditionFailed => {
+            Error::PreconditionFailed(format!("Version does not match: {}", key))
+        }
+        WriteError::InvalidValue(message) => Error::Conflict(message),
+        WriteError::KeyTooLong(max) => {
+            Error::BadRequest(format!("Key longer than {} bytes: {}", max, key))
+        }
+        WriteError::ValueTooLarge(max) => {
+            Error::PayloadTooLarge(format!("Value larger than {} bytes: {}", max, key))
+        }
+        WriteError::OutOfMemory if key.is_empty() => {
+            Error::InsufficientStorage("Storage limit reached".to_string())
+        }
+        WriteError::OutOfMemory => {
+            Error::InsufficientStorage(format!("Storage limit reached, cannot write: {}", key))
+        }
+        WriteError::Io(e) => storage_error(e, log),
+    }
+}
+
+/// Logs a failed write to the storage engine and converts it to an error response.
+fn storage_error(e: io::Error, log: &Logger) -> Error {
+    slog::error!(log, "Error writing to storage: {:?}", e);
+    Error::Internal("Error Writing To Storage".to_string())
 }
 
 /// Configures and launches the Rocket application.
 #[launch]
 fn rocket() -> _ {
-    let db: Db = Arc::new(Mutex::new(HashMap::new()));
-    let db_clone = Arc::clone(&db);
-    task::spawn(cleanup_expired_keys(db_clone));
+    let rocket = rocket::build();
+    let config: config::Config = rocket.figment().extract().expect("invalid configuration");
+    let log = build_logger();
+    let namespaces = Namespaces::open(&config, log.clone()).expect("failed to open storage engine");
+    mount(rocket, &config, namespaces, log)
+}
 
+/// Mounts the routes on `rocket`, serving `namespaces` as `config` sets out and logging
+/// to `log`.
+fn mount(
+    rocket: Rocket<Build>,
+    config: &config::Config,
+    namespaces: Namespaces,
+    log: Logger
+) -> Rocket<Build> {
     rocket
-        ::build()
-        .manage(db)
-        .manage(build_logger())
-        .mount("/", routes![get, get_all, get_ttl, create, delete])
+        .attach(namespace::Router)
+        .manage(namespaces)
+        .manage(Limits::new(config))
+        .manage(Credentials::new(config))
+        .manage(log)
+        .mount("/", routes![
+                get,
+                get_all,
+                get_ttl,
+                expire,
+                expire_at,
+                persist,
+                create,
+                create_bytes,
+                delete,
+                incr,
+                decr,
+                json_patch,
+                merge_patch,
+                unsupported_patch,
+                batch_get,
+                batch_set,
+                batch_delete,
+                lookup,
+                list_indexes,
+                create_index,
+                drop_index,
+                list_namespaces,
+                create_namespace,
+                stats,
+                flush,
+                get_config,
+                compact
+            ])
+        .register("/", catchers![error::default_catcher])
 }
 
 fn build_logger() -> Logger {
@@ -183,3 +1149,721 @@ fn build_logger() -> Logger {
     let drain = Mutex::new(drain).fuse();
     Logger::root(drain, o!())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eviction::Access;
+    use rocket::figment::providers::Serialized;
+    use std::collections::BTreeMap;
+    use rocket::http::Status;
+    use rocket::local::blocking::{ Client, LocalResponse };
+
+    /// Returns a client for a server with `settings`, given as they would be in `Rocket.toml`.
+    fn client(settings: Value) -> Client {
+        let rocket = rocket::custom(rocket::Config::figment().merge(Serialized::defaults(settings)));
+        let config: config::Config = rocket.figment().extract().unwrap();
+        let log = build_logger();
+        let namespaces = Namespaces::open(&config, log.clone()).unwrap();
+        Client::tracked(mount(rocket, &config, namespaces, log)).unwrap()
+    }
+
+    /// Storage keeping entries in a plain map, to test handlers apart from the storage engine.
+    #[derive(Default)]
+    struct MockStorage {
+        entries: Mutex<BTreeMap<String, Entry>>,
+        version: Mutex<u64>,
+    }
+
+    impl MockStorage {
+        fn entry(&self, value: Value) -> Entry {
+            let mut version = self.version.lock().unwrap();
+            *version += 1;
+            Entry {
+                value: Data::Json(Arc::new(value)),
+                expiry: None,
+                logged_expiry: None,
+                sliding: None,
+                version: *version,
+                access: Access::new(),
+            }
+        }
+
+        fn value(&self, key: &str) -> Option<Value> {
+            match &self.entries.lock().unwrap().get(key)?.value {
+                Data::Json(value) => Some(Value::clone(value)),
+                Data::Bytes { .. } => None,
+            }
+        }
+    }
+
+    impl Storage for MockStorage {
+        fn get(&self, key: &str) -> Option<Entry> {
+            self.peek(key)
+        }
+
+        fn peek(&self, key: &str) -> Option<Entry> {
+            self.entries.lock().unwrap().get(key).cloned()
+        }
+
+        fn set(
+            &self,
+            key: &str,
+            value: Data,
+            expiry: ExpiryTime,
+            sliding: Option<Duration>,
+            precondition: &Precondition
+        ) -> Result<u64, WriteError> {
+            let entry = self.update(key, precondition, &mut (|_| Ok((value.clone(), expiry, sliding))))?;
+            Ok(entry.version)
+        }
+
+        fn update(
+            &self,
+            key: &str,
+            precondition: &Precondition,
+            update: &mut engine::Update
+        ) -> Result<Entry, WriteError> {
+            let mut entries = self.entries.lock().unwrap();
+            let current = entries.get(key);
+            precondition.check(current.map(|entry| entry.version))?;
+            let (value, expiry, sliding) = update(current)?;
+            let entry = Entry { value, expiry, sliding, ..self.entry(Value::Null) };
+            entries.insert(key.to_string(), entry.clone());
+            Ok(entry)
+        }
+
+        fn delete(&self, key: &str, precondition: &Precondition) -> Result<bool, WriteError> {
+            let mut entries = self.entries.lock().unwrap();
+            precondition.check(entries.get(key).map(|entry| entry.version))?;
+            Ok(entries.remove(key).is_some())
+        }
+
+        fn get_many(&self, keys: &[&str]) -> Vec<Option<Entry>> {
+            keys.iter().map(|key| self.peek(key)).collect()
+        }
+
+        fn set_many(&self, entries: Vec<(String, Data, ExpiryTime)>) -> Result<Vec<u64>, WriteError> {
+            let versions = entries
+                .into_iter()
+                .map(|(key, value, expiry)| {
+                    let entry = Entry { value, expiry, ..self.entry(Value::Null) };
+                    let version = entry.version;
+                    self.entries.lock().unwrap().insert(key, entry);
+                    version
+                })
+                .collect();
+            Ok(versions)
+        }
+
+        fn delete_many(&self, keys: &[&str], atomic: bool) -> Result<Vec<bool>, WriteError> {
+            let mut entries = self.entries.lock().unwrap();
+            let existed: Vec<bool> = keys.iter().map(|key| entries.contains_key(*key)).collect();
+            if !(atomic && existed.contains(&false)) {
+                for key in keys {
+                    entries.remove(*key);
+                }
+            }
+            Ok(existed)
+        }
+
+        fn create_index(&self, _: &str, _: Path) -> io::Result<bool> {
+            Ok(false)
+        }
+
+        fn drop_index(&self, _: &str) -> io::Result<bool> {
+            Ok(false)
+        }
+
+        fn indexes(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn lookup(&self, _: &str, _: &Value) -> Option<Vec<(String, Entry)>> {
+            None
+        }
+
+        fn memory(&self) -> usize {
+            0
+        }
+
+        fn flush(&self) -> io::Result<usize> {
+            let mut entries = self.entries.lock().unwrap();
+            let count = entries.len();
+            entries.clear();
+            Ok(count)
+        }
+
+        fn scan(&self, visit: &mut dyn FnMut(&str, &Entry)) {
+            for (key, entry) in self.entries.lock().unwrap().iter() {
+                visit(key, entry);
+            }
+        }
+    }
+
+    /// Returns a client for a server whose namespaces all store their entries in `mock`.
+    fn mock_client(mock: &Arc<MockStorage>) -> Client {
+        let config = config::Config::default();
+        let mock = Arc::clone(mock);
+        let log = build_logger();
+        let namespaces = Namespaces::with_engine(&config, log.clone(), move |_, _, _| {
+            Ok(Arc::clone(&mock) as Db)
+        }).unwrap();
+        Client::tracked(mount(rocket::build(), &config, namespaces, log)).unwrap()
+    }
+
+    fn with_keys() -> Client {
+        client(json!({ "read_only_keys": ["ro"], "read_write_keys": ["rw"] }))
+    }
+
+    fn insert(client: &Client, key: &str, value: Value) {
+        let response = client.post(format!("/{}", key)).header(ContentType::JSON);
+        let response = response.body(value.to_string());
+        assert_eq!(response.dispatch().status(), Status::Ok);
+    }
+
+    /// Returns a client whose API keys are limited by access control rules.
+    fn with_rules() -> Client {
+        let all = ["get", "set", "delete", "list", "admin"];
+        let users = ["get", "set", "delete", "list"];
+        client(
+            json!({
+                "namespaces": ["billing"],
+                "indexes": ["$.team"],
+                "acl": [
+                    { "key": "root", "operations": all },
+                    { "key": "users", "prefixes": ["user:"], "operations": users },
+                    { "key": "lister", "prefixes": ["user:"], "operations": ["list"] },
+                    { "key": "billing", "namespaces": ["billing"], "operations": all },
+                ],
+            })
+        )
+    }
+
+    fn api_key(key: &'static str) -> Header<'static> {
+        Header::new("X-API-Key", key)
+    }
+
+    /// Sends `body` as JSON to `uri` with the API key `key`, returning the status and body.
+    fn post_as(client: &Client, key: &'static str, uri: &str, body: Value) -> (Status, Value) {
+        let response = client
+            .post(uri.to_string())
+            .header(ContentType::JSON)
+            .header(api_key(key))
+            .body(body.to_string())
+            .dispatch();
+        (response.status(), response.into_json().unwrap_or(Value::Null))
+    }
+
+    fn post(client: &Client, uri: &str, body: Value) -> (Status, Value) {
+        let response = client.post(uri.to_string()).header(ContentType::JSON);
+        let response = response.body(body.to_string()).dispatch();
+        (response.status(), response.into_json().unwrap_or(Value::Null))
+    }
+
+    fn get_as(client: &Client, key: &'static str, uri: &str) -> (Status, Value) {
+        let response = client.get(uri.to_string()).header(api_key(key)).dispatch();
+        (response.status(), response.into_json().unwrap_or(Value::Null))
+    }
+
+    fn statuses(body: &Value) -> Vec<u64> {
+        let results = body["results"].as_array().unwrap();
+        results.iter().map(|result| result["status"].as_u64().unwrap()).collect()
+    }
+
+    fn error(response: LocalResponse) -> String {
+        let body: Value = response.into_json().unwrap();
+        body["status"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn no_keys_configured_needs_no_credentials() {
+        let client = client(json!({}));
+        let response = client.post("/a").header(ContentType::JSON).body("1").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(client.get("/a").dispatch().into_string().unwrap(), "1");
+    }
+
+    #[test]
+    fn missing_key_is_unauthorized() {
+        let client = with_keys();
+        let response = client.get("/a").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("WWW-Authenticate"), Some("Bearer"));
+        assert_eq!(error(response), "Missing API key");
+    }
+
+    #[test]
+    fn invalid_key_is_unauthorized() {
+        let client = with_keys();
+        let response = client.get("/a").header(Header::new("X-API-Key", "nope")).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(error(response), "Invalid API key");
+    }
+
+    #[test]
+    fn missing_key_is_unauthorized_before_the_body_is_read() {
+        let client = with_keys();
+        let response = client.post("/a").header(ContentType::JSON).body("{").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn read_only_key_cannot_write() {
+        let client = with_keys();
+        let key = Header::new("X-API-Key", "ro");
+        let response = client
+            .post("/a")
+            .header(ContentType::JSON)
+            .header(key.clone())
+            .body("1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(error(response), "Credentials do not allow set on key: a");
+        assert_eq!(client.delete("/a").header(key.clone()).dispatch().status(), Status::Forbidden);
+        assert_eq!(client.post("/admin/flush").header(key.clone()).dispatch().status(), Status::Forbidden);
+        assert_eq!(client.get("/admin/config").header(key).dispatch().status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn read_only_key_reads_what_read_write_key_writes() {
+        let client = with_keys();
+        let response = client
+            .post("/a")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer rw"))
+            .body("1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/a").header(Header::new("X-API-Key", "ro")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), "1");
+        let response = client
+            .get("/?keys_only=true")
+            .header(Header::new("Authorization", "bearer ro"))
+            .dispatch();
+        assert_eq!(response.into_json::<Vec<String>>().unwrap(), vec!["a"]);
+    }
+
+    #[test]
+    fn get_reads_from_storage() {
+        let mock = Arc::new(MockStorage::default());
+        let entry = mock.entry(json!({ "name": "Ada" }));
+        mock.entries.lock().unwrap().insert("user".to_string(), entry);
+        let client = mock_client(&mock);
+
+        let response = client.get("/user").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"1\""));
+        assert_eq!(response.into_json::<Value>().unwrap(), json!({ "name": "Ada" }));
+        let response = client.get("/user?path=$.name").dispatch();
+        assert_eq!(response.into_json::<Value>().unwrap(), json!("Ada"));
+    }
+
+    #[test]
+    fn create_and_delete_write_to_storage() {
+        let mock = Arc::new(MockStorage::default());
+        let client = mock_client(&mock);
+
+        let response = client.post("/a").header(ContentType::JSON).body("[1, 2]").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(mock.value("a"), Some(json!([1, 2])));
+
+        assert_eq!(client.delete("/a").dispatch().status(), Status::Ok);
+        assert_eq!(mock.value("a"), None);
+        assert_eq!(client.delete("/a").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn conditional_write_checks_the_stored_version() {
+        let mock = Arc::new(MockStorage::default());
+        let entry = mock.entry(json!(1));
+        mock.entries.lock().unwrap().insert("a".to_string(), entry);
+        let client = mock_client(&mock);
+
+        let response = client
+            .post("/a")
+            .header(ContentType::JSON)
+            .header(Header::new("If-Match", "\"5\""))
+            .body("2")
+            .dispatch();
+        assert_eq!(response.status(), Status::PreconditionFailed);
+        assert_eq!(mock.value("a"), Some(json!(1)));
+
+        let response = client
+            .post("/a")
+            .header(ContentType::JSON)
+            .header(Header::new("If-None-Match", "*"))
+            .body("2")
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn incr_updates_storage() {
+        let mock = Arc::new(MockStorage::default());
+        let client = mock_client(&mock);
+
+        assert_eq!(client.post("/incr/hits?by=5").dispatch().status(), Status::Ok);
+        assert_eq!(client.post("/decr/hits").dispatch().status(), Status::Ok);
+        assert_eq!(mock.value("hits"), Some(json!(4)));
+    }
+
+    #[test]
+    fn listing_encodes_bytes_that_are_not_utf8_in_base64() {
+        let client = client(json!({}));
+        let binary = ContentType::new("application", "octet-stream");
+        for (key, body) in [("a", &b"text"[..]), ("b", b"\xff\x00"), ("c", b"\xfeAB\x00")] {
+            let response = client.post(format!("/{}", key)).header(binary.clone()).body(body);
+            assert_eq!(response.dispatch().status(), Status::Ok);
+        }
+
+        let listing = client.get("/").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(listing["a"]["value"], "text");
+        assert_eq!(listing["a"].get("encoding"), None);
+        assert_eq!(listing["b"]["value"], "/wA=");
+        assert_eq!(listing["b"]["encoding"], "base64");
+        assert_eq!(listing["c"]["value"], "/kFCAA==");
+    }
+
+    #[test]
+    fn listing_pages_in_key_order() {
+        let client = client(json!({}));
+        for key in ["user:3", "user:1", "order:1", "user:2"] {
+            insert(&client, key, json!(key));
+        }
+
+        let page = client.get("/?limit=2").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(page["cursor"], "user:1");
+        assert_eq!(page["entries"][0]["key"], "order:1");
+        assert_eq!(page["entries"][1]["key"], "user:1");
+        assert_eq!(page["entries"][1]["value"], "user:1");
+        assert_eq!(page["entries"].as_array().unwrap().len(), 2);
+
+        let page = client.get("/?limit=2&cursor=user:1").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(page["cursor"], "user:3");
+        let page = client.get("/?limit=2&cursor=user:3").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(page, json!({ "cursor": null, "entries": [] }));
+    }
+
+    #[test]
+    fn listing_filters_by_prefix_and_pattern_with_keys_only() {
+        let client = client(json!({}));
+        for key in ["user:1", "user:22", "order:1"] {
+            insert(&client, key, json!(key));
+        }
+        let keys = |query: &str| client.get(query).dispatch().into_json::<Value>().unwrap();
+        assert_eq!(keys("/?keys_only=true"), json!(["order:1", "user:1", "user:22"]));
+        assert_eq!(keys("/?keys_only=true&pattern=user:?"), json!(["user:1"]));
+        assert_eq!(keys("/?keys_only=true&pattern=*:1"), json!(["order:1", "user:1"]));
+        assert_eq!(
+            keys("/?keys_only=true&prefix=user:&limit=1"),
+            json!({ "cursor": "user:1", "keys": ["user:1"] })
+        );
+        assert_eq!(
+            keys("/?keys_only=true&prefix=user:&limit=1&cursor=user:1"),
+            json!({ "cursor": "user:22", "keys": ["user:22"] })
+        );
+        let listing = keys("/?pattern=order:*");
+        assert_eq!(listing["order:1"]["value"], "order:1");
+        assert_eq!(listing.as_object().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn listing_rejects_invalid_limits_and_patterns() {
+        let client = client(json!({}));
+        for query in ["/?limit=0", "/?limit=-1", "/?pattern=[", "/?filter=status"] {
+            assert_eq!(client.get(query).dispatch().status(), Status::BadRequest, "{}", query);
+        }
+        assert_eq!(error(client.get("/?limit=0").dispatch()), "Invalid limit: 0");
+    }
+
+    #[test]
+    fn prefix_rules_only_allow_matching_keys() {
+        let client = with_rules();
+        assert_eq!(post_as(&client, "root", "/order:1", json!(1)).0, Status::Ok);
+        assert_eq!(post_as(&client, "users", "/user:1", json!(1)).0, Status::Ok);
+        assert_eq!(get_as(&client, "users", "/user:1"), (Status::Ok, json!(1)));
+
+        let (status, body) = post_as(&client, "users", "/order:2", json!(2));
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(body["status"], "Credentials do not allow set on key: order:2");
+        assert_eq!(get_as(&client, "users", "/order:1").0, Status::Forbidden);
+        assert_eq!(get_as(&client, "lister", "/user:1").0, Status::Forbidden);
+    }
+
+    #[test]
+    fn prefix_rules_deny_namespace_wide_actions() {
+        let client = with_rules();
+        let (status, body) = get_as(&client, "users", "/admin/stats");
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(body["status"], "Credentials do not allow list in namespace: default");
+        assert_eq!(get_as(&client, "users", "/admin/indexes").0, Status::Forbidden);
+        assert_eq!(post_as(&client, "users", "/admin/flush", json!(null)).0, Status::Forbidden);
+        assert_eq!(get_as(&client, "root", "/admin/stats").0, Status::Ok);
+    }
+
+    #[test]
+    fn namespace_rules_only_apply_to_their_namespaces() {
+        let client = with_rules();
+        assert_eq!(post_as(&client, "billing", "/ns/billing/invoice", json!(1)).0, Status::Ok);
+        assert_eq!(get_as(&client, "billing", "/ns/billing/admin/stats").0, Status::Ok);
+        assert_eq!(post_as(&client, "billing", "/invoice", json!(1)).0, Status::Forbidden);
+        assert_eq!(get_as(&client, "billing", "/admin/stats").0, Status::Forbidden);
+
+        let namespaces = |key| get_as(&client, key, "/admin/namespaces").1;
+        assert_eq!(namespaces("billing"), json!({ "namespaces": ["billing"] }));
+        assert_eq!(namespaces("root"), json!({ "namespaces": ["billing", "default"] }));
+    }
+
+    #[test]
+    fn listings_and_lookups_only_return_visible_keys() {
+        let client = with_rules();
+        for key in ["user:1", "order:1"] {
+            post_as(&client, "root", &format!("/{}", key), json!({ "team": "x" }));
+        }
+
+        assert_eq!(get_as(&client, "users", "/?keys_only=true").1, json!(["user:1"]));
+        let listing = get_as(&client, "users", "/").1;
+        assert_eq!(listing.as_object().unwrap().keys().collect::<Vec<_>>(), ["user:1"]);
+        // Listing values also needs `get`.
+        assert_eq!(get_as(&client, "lister", "/?keys_only=true").1, json!(["user:1"]));
+        assert_eq!(get_as(&client, "lister", "/").1, json!({}));
+        let page = get_as(&client, "lister", "/?limit=10").1;
+        assert_eq!(page, json!({ "cursor": null, "entries": [] }));
+
+        let lookup = |key, keys_only| {
+            let uri = format!("/index/lookup?path=$.team&value=x&keys_only={}", keys_only);
+            get_as(&client, key, &uri).1
+        };
+        assert_eq!(lookup("root", true), json!(["order:1", "user:1"]));
+        assert_eq!(lookup("users", true), json!(["user:1"]));
+        assert_eq!(lookup("lister", true), json!(["user:1"]));
+        assert_eq!(lookup("lister", false), json!({}));
+    }
+
+    #[test]
+    fn batches_refuse_forbidden_keys_individually() {
+        let client = with_rules();
+        post_as(&client, "root", "/order:1", json!(1));
+        post_as(&client, "root", "/user:1", json!(1));
+
+        let (status, body) = post_as(&client, "users", "/batch/get", json!(["user:1", "order:1"]));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(statuses(&body), [200, 403]);
+        assert_eq!(body["results"][0]["value"], 1);
+
+        let items = json!([{ "key": "user:2", "value": 2 }, { "key": "order:2", "value": 2 }]);
+        let (_, body) = post_as(&client, "users", "/batch/set", items);
+        assert_eq!(statuses(&body), [200, 403]);
+        assert_eq!(get_as(&client, "root", "/user:2").0, Status::Ok);
+        assert_eq!(get_as(&client, "root", "/order:2").0, Status::NotFound);
+
+        let (_, body) = post_as(&client, "users", "/batch/delete", json!(["user:2", "order:1"]));
+        assert_eq!(statuses(&body), [200, 403]);
+        assert_eq!(body["status"], "Keys deleted: 1");
+        assert_eq!(get_as(&client, "root", "/order:1").0, Status::Ok);
+    }
+
+    #[test]
+    fn atomic_batches_are_refused_whole_for_a_forbidden_key() {
+        let client = with_rules();
+        post_as(&client, "root", "/order:1", json!(1));
+        post_as(&client, "root", "/user:1", json!(1));
+
+        let keys = json!(["user:1", "order:1"]);
+        let (status, _) = post_as(&client, "users", "/batch/get?atomic=true", keys.clone());
+        assert_eq!(status, Status::Forbidden);
+        let items = json!([{ "key": "user:2", "value": 2 }, { "key": "order:2", "value": 2 }]);
+        assert_eq!(post_as(&client, "users", "/batch/set?atomic=true", items).0, Status::Forbidden);
+        assert_eq!(get_as(&client, "root", "/user:2").0, Status::NotFound);
+        let (status, _) = post_as(&client, "users", "/batch/delete?atomic=true", keys);
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(get_as(&client, "root", "/user:1").0, Status::Ok);
+    }
+
+    #[test]
+    fn namespace_routes_are_rewritten_with_their_query() {
+        let client = client(json!({ "namespaces": ["team"] }));
+        insert(&client, "ns/team/user:1", json!({ "name": "Ada" }));
+        insert(&client, "ns/team/user:2", json!({ "name": "Grace" }));
+
+        let response = client.get("/ns/team/user:1?path=$.name").dispatch();
+        assert_eq!(response.into_json::<Value>().unwrap(), json!("Ada"));
+        let list = |uri| client.get(uri).dispatch().into_json::<Value>().unwrap();
+        let page = list("/ns/team?keys_only=true&limit=1");
+        assert_eq!(page, json!({ "cursor": "user:1", "keys": ["user:1"] }));
+        assert_eq!(list("/ns/team/?keys_only=true"), json!(["user:1", "user:2"]));
+    }
+
+    #[test]
+    fn unknown_namespaces_are_not_found() {
+        let client = client(json!({}));
+        let response = client.get("/ns/missing/a").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(error(response), "Namespace not found: missing");
+        let response = client.post("/ns/missing/a").header(ContentType::JSON).body("1").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(client.get("/ns/missing/admin/stats").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        let client = client(json!({}));
+        let response = client.post("/admin/namespaces/team").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(client.post("/admin/namespaces/team").dispatch().status(), Status::Conflict);
+        let response = client.post("/admin/namespaces/bad.name").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        insert(&client, "a", json!("default"));
+        insert(&client, "ns/team/a", json!("team"));
+        insert(&client, "ns/team/b", json!("team"));
+        assert_eq!(client.get("/a").dispatch().into_string().unwrap(), "\"default\"");
+        assert_eq!(client.get("/ns/team/a").dispatch().into_string().unwrap(), "\"team\"");
+        assert_eq!(client.get("/b").dispatch().status(), Status::NotFound);
+
+        let stats = |uri| client.get(uri).dispatch().into_json::<Value>().unwrap();
+        assert_eq!(stats("/ns/team/admin/stats")["keys"], 2);
+        assert_eq!(stats("/ns/team/admin/stats")["namespace"], "team");
+        assert_eq!(stats("/admin/stats")["keys"], 1);
+
+        let flushed = client.post("/ns/team/admin/flush").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(flushed["deleted"], 2);
+        assert_eq!(stats("/ns/team/admin/stats")["keys"], 0);
+        assert_eq!(client.get("/a").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn namespaces_are_found_again_on_disk_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("micro_kv-namespaces-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let settings = json!({ "aof_path": dir.join("appendonly.aof"), "aof_fsync": "always" });
+        {
+            let client = client(settings.clone());
+            assert_eq!(client.post("/admin/namespaces/team").dispatch().status(), Status::Ok);
+            insert(&client, "ns/team/a", json!(1));
+            insert(&client, "a", json!(2));
+        }
+
+        let client = client(settings);
+        let namespaces = client.get("/admin/namespaces").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(namespaces, json!({ "namespaces": ["default", "team"] }));
+        assert_eq!(client.get("/ns/team/a").dispatch().into_string().unwrap(), "1");
+        assert_eq!(client.get("/a").dispatch().into_string().unwrap(), "2");
+    }
+
+    #[test]
+    fn keepttl_keeps_the_ttl_of_an_overwritten_key() {
+        let client = client(json!({}));
+        let write = |uri: &str, body: &str| {
+            let request = client.post(uri.to_string()).header(ContentType::JSON);
+            request.body(body).dispatch().status()
+        };
+        let ttl = || client.get("/ttl/a").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(write("/a?ttl=100&sliding=true", "1"), Status::Ok);
+        assert_eq!(write("/a?keepttl=true", "2"), Status::Ok);
+        assert!(ttl()["ttl"].as_f64().unwrap() > 90.0);
+        assert_eq!(ttl()["sliding"], true);
+        assert_eq!(client.get("/a").dispatch().into_string().unwrap(), "2");
+
+        assert_eq!(write("/a", "3"), Status::Ok);
+        assert_eq!(ttl()["ttl"], Value::Null);
+        assert_eq!(ttl()["sliding"], false);
+        assert_eq!(write("/a?keepttl=true&ttl=5", "4"), Status::BadRequest);
+    }
+
+    #[test]
+    fn reads_renew_a_sliding_ttl_but_ttl_checks_do_not() {
+        let client = client(json!({}));
+        let response = client.post("/a?ttl_ms=400&sliding=true").header(ContentType::JSON);
+        assert_eq!(response.body("1").dispatch().status(), Status::Ok);
+        let ttl_ms = || {
+            let ttl = client.get("/ttl/a").dispatch().into_json::<Value>().unwrap();
+            ttl["ttl_ms"].as_u64()
+        };
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(ttl_ms().unwrap() <= 200);
+        assert_eq!(client.get("/a").dispatch().into_string().unwrap(), "1");
+        assert!(ttl_ms().unwrap() > 300);
+    }
+
+    #[test]
+    fn indexes_created_at_runtime_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("micro_kv-indexes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let settings = json!({
+            "aof_path": dir.join("appendonly.aof"),
+            "aof_fsync": "always",
+            "indexes": ["$.team"],
+        });
+        {
+            let client = client(settings.clone());
+            insert(&client, "a", json!({ "email": "a@example.com", "team": "x" }));
+            for path in ["$.email", "$.name"] {
+                let uri = format!("/admin/indexes?path={}", path);
+                assert_eq!(client.post(uri).dispatch().status(), Status::Ok);
+            }
+            let response = client.delete("/admin/indexes?path=$.name").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            // A rewrite keeps the indexes created at runtime.
+            assert_eq!(client.post("/admin/compact").dispatch().status(), Status::Ok);
+        }
+
+        let client = client(settings);
+        let indexes = client.get("/admin/indexes").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(indexes, json!({ "indexes": ["$.email", "$.team"] }));
+        let uri = "/index/lookup?path=$.email&value=a@example.com&keys_only=true";
+        assert_eq!(client.get(uri).dispatch().into_json::<Value>().unwrap(), json!(["a"]));
+    }
+
+    #[test]
+    fn batches_report_every_key_in_order() {
+        let client = client(json!({}));
+        insert(&client, "a", json!(1));
+        insert(&client, "b", json!(2));
+
+        let (status, body) = post(&client, "/batch/get", json!(["a", "missing", "a"]));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(statuses(&body), [200, 404, 200]);
+
+        let items = json!([
+            { "key": "c", "value": 1 },
+            { "key": "", "value": 2 },
+            { "key": "c", "value": 3 },
+        ]);
+        let (_, body) = post(&client, "/batch/set", items);
+        assert_eq!(statuses(&body), [200, 400, 200]);
+        assert_eq!(body["status"], "Keys inserted: 2");
+        assert_eq!(client.get("/c").dispatch().into_string().unwrap(), "3");
+
+        let (_, body) = post(&client, "/batch/delete", json!(["a", "a", "missing", "b"]));
+        assert_eq!(statuses(&body), [200, 404, 404, 200]);
+        assert_eq!(body["status"], "Keys deleted: 2");
+        let stats = client.get("/admin/stats").dispatch().into_json::<Value>().unwrap();
+        assert_eq!(stats["keys"], 1);
+    }
+
+    #[test]
+    fn atomic_batches_change_nothing_unless_every_key_succeeds() {
+        let client = client(json!({}));
+        insert(&client, "a", json!(1));
+
+        let (status, body) = post(&client, "/batch/get?atomic=true", json!(["a", "missing"]));
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(body["status"], "Key not found: missing");
+        let items = json!([{ "key": "a", "value": 2 }, { "key": "", "value": 3 }]);
+        assert_eq!(post(&client, "/batch/set?atomic=true", items).0, Status::BadRequest);
+        assert_eq!(client.get("/a").dispatch().into_string().unwrap(), "1");
+
+        for keys in [json!(["a", "missing"]), json!(["a", "a"])] {
+            assert_eq!(post(&client, "/batch/delete?atomic=true", keys).0, Status::NotFound);
+            assert_eq!(client.get("/a").dispatch().status(), Status::Ok);
+        }
+        let (status, body) = post(&client, "/batch/delete?atomic=true", json!(["a"]));
+        assert_eq!((status, body["status"].as_str()), (Status::Ok, Some("Keys deleted: 1")));
+        assert_eq!(client.get("/a").dispatch().status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::Config;
+use crate::engine;
+use crate::error::{ self, Error };
+use crate::limits::{ Capacity, Limits };
+use crate::Db;
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::http::uri::Origin;
+use rocket::request::{ FromRequest, Outcome, Request };
+use rocket::Data;
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, RwLock };
+
+/// Name of the namespace served by the routes outside of `/ns/<name>`.
+pub const DEFAULT: &str = "default";
+/// Directory, next to the files of the default namespace, holding those of the others.
+const DIR: &str = "namespaces";
+
+/// Named, independent databases, each with its own entries, indexes and persistence files,
+/// that share a single entry and memory limit.
+///
+/// The files of a namespace live in a `namespaces/<name>` directory next to the append-only
+/// log and within the snapshot directory of the default namespace, so namespaces with files
+/// on disk are opened again on startup.
+pub struct Namespaces {
+    config: Config,
+    capacity: Arc<Capacity>,
+    namespaces: RwLock<BTreeMap<String, Db>>,
+    open: Box<Open>,
+    /// Logger the storage of each namespace reports background errors to.
+    log: Logger,
+}
+
+/// Opens the storage of a namespace with its configuration, counting its entries towards the
+/// shared capacity and reporting errors to the logger.
+pub type Open = dyn Fn(&Config, Arc<Capacity>, &Logger) -> io::Result<Db> + Send + Sync;
+
+impl Namespaces {
+    /// Opens the default namespace, those listed in `config` and those found on disk, with
+    /// the storage engine selected by `config`.
+    pub fn open(config: &Config, log: Logger) -> io::Result<Self> {
+        Namespaces::with_engine(config, log, engine::open)
+    }
+
+    /// Opens the default namespace, those listed in `config` and those found on disk, with
+    /// the storage returned by `open`, such as a mock.
+    pub fn with_engine(
+        config: &Config,
+        log: Logger,
+        open: impl Fn(&Config, Arc<Capacity>, &Logger) -> io::Result<Db> + Send + Sync + 'static
+    ) -> io::Result<Self> {
+        let namespaces = Namespaces {
+            config: config.clone(),
+            capacity: Arc::new(Capacity::new(Limits::new(config))),
+            namespaces: RwLock::new(BTreeMap::new()),
+            open: Box::new(open),
+            log,
+        };
+        namespaces.create(DEFAULT)?;
+        for name in config.namespaces.iter().chain(&namespaces.discover()?) {
+            if !is_valid(name) {
+                let message = format!("invalid namespace name: {}", name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            namespaces.create(name)?;
+        }
+        Ok(namespaces)
+    }
+
+    /// Returns the storage of the namespace `name`, if it exists.
+    pub fn get(&self, name: &str) -> Option<Db> {
+        self.namespaces.read().unwrap().get(name).cloned()
+    }
+
+    /// Creates and opens the namespace `name`, returning false if it already exists.
+    pub fn create(&self, name: &str) -> io::Result<bool> {
+        let mut namespaces = self.namespaces.write().unwrap();
+        if namespaces.contains_key(name) {
+            return Ok(false);
+        }
+        let config = self.config(name);
+        if let Some(dir) = config.aof_path.as_deref().and_then(Path::parent) {
+            fs::create_dir_all(dir)?;
+        }
+        let db = (self.open)(&config, Arc::clone(&self.capacity), &self.log)?;
+        namespaces.insert(name.to_string(), db);
+        Ok(true)
+    }
+
+    /// Returns the names of all namespaces, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.namespaces.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the configuration of the namespace `name`, with its own persistence files.
+    fn config(&self, name: &str) -> Config {
+        let mut config = self.config.clone();
+        if name == DEFAULT {
+            return config;
+        }
+        config.aof_path = self.config.aof_path.as_deref().map(|path| {
+            let file_name = path.file_name().unwrap_or("appendonly.aof".as_ref());
+            aof_dir(path).join(name).join(file_name)
+        });
+        config.snapshot_dir = self.config.snapshot_dir.as_ref().map(|dir| dir.join(DIR).join(name));
+        config
+    }
+
+    /// Returns the names of the namespaces with persistence files.
+    fn discover(&self) -> io::Result<Vec<String>> {
+        let dirs = [
+            self.config.aof_path.as_deref().map(aof_dir),
+            self.config.snapshot_dir.as_ref().map(|dir| dir.join(DIR)),
+        ];
+        let mut names = Vec::new();
+        for dir in dirs.into_iter().flatten().filter(|dir| dir.is_dir()) {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    names.extend(entry.file_name().into_string().ok().filter(|name| is_valid(name)));
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Returns the directory holding the append-only logs of the namespaces other than the default.
+fn aof_dir(path: &Path) -> PathBuf {
+    path.parent().unwrap_or("".as_ref()).join(DIR)
+}
+
+/// Returns true if `name` can name a namespace: up to 64 ASCII letters, digits, `-` and `_`.
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty() &&
+        name.len() <= 64 &&
+        name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+/// The namespace a request addresses, set by `Router`.
+struct Selected(Option<String>);
+
+/// Serves the routes of every namespace under `/ns/<name>` by stripping that prefix from
+/// the request URI, so `/ns/<name>/<key>` is handled like `/<key>` in that namespace.
+pub struct Router;
+
+#[rocket::async_trait]
+impl Fairing for Router {
+    fn info(&self) -> Info {
+        Info { name: "Namespace routing", kind: Kind::Request }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(rest) = req.uri().path().as_str().strip_prefix("/ns/") else {
+            return;
+        };
+        let (name, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let name = name.to_string();
+        let uri = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query.as_str()),
+            None => path.to_string(),
+        };
+        if let Ok(uri) = Origin::parse_owned(uri) {
+            req.set_uri(uri);
+            req.local_cache(|| Selected(Some(name)));
+        }
+    }
+}
+
+/// The storage of the namespace a request addresses, or of the default namespace for
+/// requests outside of `/ns/<name>`.
+pub struct Namespace {
+    pub name: String,
+    db: Db,
+}
+
+impl Deref for Namespace {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Namespace {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let name = req.local_cache(|| Selected(None)).0.as_deref().unwrap_or(DEFAULT);
+        let namespaces = req.rocket().state::<Namespaces>().expect("namespaces are managed");
+        match namespaces.get(name) {
+            Some(db) => Outcome::Success(Namespace { name: name.to_string(), db }),
+            None => error::fail(req, Error::NamespaceNotFound(name.to_string())),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A single operation of a JSON Patch document (RFC 6902).
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add {
+        path: String,
+        value: Value,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        value: Value,
+    },
+    Move {
+        from: String,
+        path: String,
+    },
+    Copy {
+        from: String,
+        path: String,
+    },
+    Test {
+        path: String,
+        value: Value,
+    },
+}
+
+/// Applies the JSON Patch `operations` to `doc` in order.
+///
+/// On error `doc` may be partly patched, so callers apply patches to a copy.
+pub fn apply(doc: &mut Value, operations: &[Operation]) -> Result<(), String> {
+    for operation in operations {
+        match operation {
+            Operation::Add { path, value } => add(doc, path, value.clone())?,
+            Operation::Remove { path } => {
+                remove(doc, path)?;
+            }
+            Operation::Replace { path, value } => {
+                *lookup(doc, path)? = value.clone();
+            }
+            Operation::Move { from, path } => {
+                if path.starts_with(from.as_str()) && path[from.len()..].starts_with('/') {
+                    return Err(format!("Cannot move {} into itself", from));
+                }
+                let value = remove(doc, from)?;
+                add(doc, path, value)?;
+            }
+            Operation::Copy { from, path } => {
+                let value = lookup(doc, from)?.clone();
+                add(doc, path, value)?;
+            }
+            Operation::Test { path, value } => {
+                if *lookup(doc, path)? != *value {
+                    return Err(format!("Test failed: {}", path));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies the JSON Merge Patch `patch` to `doc` (RFC 7396).
+pub fn merge(doc: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *doc = patch.clone();
+        return;
+    };
+    if !doc.is_object() {
+        *doc = Value::Object(Default::default());
+    }
+    if let Value::Object(doc) = doc {
+        for (name, value) in patch {
+            if value.is_null() {
+                doc.remove(name);
+            } else {
+                merge(doc.entry(name.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+fn lookup<'a>(doc: &'a mut Value, path: &str) -> Result<&'a mut Value, String> {
+    doc.pointer_mut(path).ok_or_else(|| format!("Path not found: {}", path))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split(path)?;
+    match lookup(doc, parent)? {
+        Value::Object(object) => {
+            object.insert(token, value);
+        }
+        Value::Array(array) => {
+            // "-" appends; an index may also point just past the last element.
+            let index = match token.as_str() {
+                "-" => array.len(),
+                token => index(token, array.len() + 1, path)?,
+            };
+            array.insert(index, value);
+        }
+        _ => {
+            return Err(format!("Path not found: {}", path));
+        }
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (parent, token) = split(path)?;
+    let removed = match lookup(doc, parent)? {
+        Value::Object(object) => object.remove(&token),
+        Value::Array(array) => {
+            let index = index(&token, array.len(), path)?;
+            Some(array.remove(index))
+        }
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("Path not found: {}", path))
+}
+
+/// Splits a non-empty JSON Pointer into the pointer to its parent and its unescaped last token.
+fn split(path: &str) -> Result<(&str, String), String> {
+    let (parent, token) = path.rsplit_once('/').ok_or_else(|| format!("Invalid path: {}", path))?;
+    if !path.starts_with('/') {
+        return Err(format!("Invalid path: {}", path));
+    }
+    Ok((parent, token.replace("~1", "/").replace("~0", "~")))
+}
+
+/// Parses an array index, which must be below `bound` and have no leading zeros.
+fn index(token: &str, bound: usize, path: &str) -> Result<usize, String> {
+    let digits = !token.is_empty() && token.bytes().all(|byte| byte.is_ascii_digit());
+    let valid = digits && (token == "0" || !token.starts_with('0'));
+    token
+        .parse()
+        .ok()
+        .filter(|index| valid && *index < bound)
+        .ok_or_else(|| format!("Path not found: {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patched(mut doc: Value, operations: Value) -> Result<Value, String> {
+        let operations: Vec<Operation> = serde_json::from_value(operations).unwrap();
+        apply(&mut doc, &operations)?;
+        Ok(doc)
+    }
+
+    #[test]
+    fn applies_operations_in_order() {
+        let doc = json!({ "name": "Ada", "tags": ["a"], "old": 1 });
+        let operations = json!([
+            { "op": "add", "path": "/tags/-", "value": "b" },
+            { "op": "add", "path": "/tags/0", "value": "z" },
+            { "op": "replace", "path": "/name", "value": "Grace" },
+            { "op": "remove", "path": "/old" },
+            { "op": "copy", "from": "/name", "path": "/alias" },
+            { "op": "move", "from": "/alias", "path": "/nick" },
+            { "op": "test", "path": "/nick", "value": "Grace" },
+        ]);
+        let expected = json!({ "name": "Grace", "tags": ["z", "a", "b"], "nick": "Grace" });
+        assert_eq!(patched(doc, operations), Ok(expected));
+    }
+
+    #[test]
+    fn unescapes_path_tokens() {
+        let operations = json!([{ "op": "add", "path": "/a~1b~0c", "value": 1 }]);
+        assert_eq!(patched(json!({}), operations), Ok(json!({ "a/b~c": 1 })));
+    }
+
+    #[test]
+    fn adding_at_the_root_replaces_the_document() {
+        let operations = json!([{ "op": "add", "path": "", "value": [1] }]);
+        assert_eq!(patched(json!({ "a": 1 }), operations), Ok(json!([1])));
+    }
+
+    #[test]
+    fn rejects_missing_paths_and_bad_indexes() {
+        let cases = [
+            json!([{ "op": "remove", "path": "/missing" }]),
+            json!([{ "op": "replace", "path": "/missing", "value": 1 }]),
+            json!([{ "op": "add", "path": "/missing/a", "value": 1 }]),
+            json!([{ "op": "add", "path": "/list/5", "value": 1 }]),
+            json!([{ "op": "remove", "path": "/list/01" }]),
+            json!([{ "op": "add", "path": "relative", "value": 1 }]),
+        ];
+        for operations in cases {
+            assert!(patched(json!({ "list": [1, 2] }), operations.clone()).is_err(), "{}", operations);
+        }
+    }
+
+    #[test]
+    fn failed_test_stops_the_patch() {
+        let operations = json!([{ "op": "test", "path": "/a", "value": 2 }]);
+        assert_eq!(patched(json!({ "a": 1 }), operations), Err("Test failed: /a".to_string()));
+    }
+
+    #[test]
+    fn cannot_move_a_value_into_itself() {
+        let operations = json!([{ "op": "move", "from": "/a", "path": "/a/b" }]);
+        assert!(patched(json!({ "a": {} }), operations).is_err());
+    }
+}
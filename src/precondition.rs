@@ -0,0 +1,143 @@
+use crate::engine::WriteError;
+use crate::error::Error;
+use rocket::http::Status;
+use rocket::request::{ FromRequest, Outcome, Request };
+
+/// A set of entity tags from an `If-Match` or `If-None-Match` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tags {
+    /// `*`, matching any existing entry.
+    Any,
+    /// A list of entry versions.
+    Versions(Vec<u64>),
+}
+
+impl Tags {
+    fn matches(&self, version: Option<u64>) -> bool {
+        match (self, version) {
+            (_, None) => false,
+            (Tags::Any, Some(_)) => true,
+            (Tags::Versions(versions), Some(version)) => versions.contains(&version),
+        }
+    }
+
+    /// Parses a header value such as `*` or `"3", "4"`.
+    fn parse(header: &str) -> Option<Self> {
+        if header.trim() == "*" {
+            return Some(Tags::Any);
+        }
+        header
+            .split(',')
+            .map(|tag| {
+                let tag = tag.trim();
+                let tag = tag.strip_prefix("W/").unwrap_or(tag);
+                tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+            })
+            .collect::<Option<Vec<u64>>>()
+            .map(Tags::Versions)
+    }
+}
+
+/// Conditions on the current version of an entry that must hold for a write to go ahead,
+/// taken from the `If-Match` and `If-None-Match` request headers.
+#[derive(Debug, Clone, Default)]
+pub struct Precondition {
+    pub if_match: Option<Tags>,
+    pub if_none_match: Option<Tags>,
+}
+
+impl Precondition {
+    /// Checks the precondition against the version of the entry, or `None` if there is none.
+    pub fn check(&self, version: Option<u64>) -> Result<(), WriteError> {
+        if self.if_match.as_ref().is_some_and(|tags| !tags.matches(version)) {
+            return Err(WriteError::PreconditionFailed);
+        }
+        match &self.if_none_match {
+            Some(Tags::Any) if version.is_some() => Err(WriteError::KeyExists),
+            Some(tags) if tags.matches(version) => Err(WriteError::PreconditionFailed),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Formats `version` as the value of an `ETag` header.
+pub fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Precondition {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let parse = |name: &str| {
+            req.headers()
+                .get_one(name)
+                .map(|value| {
+                    Tags::parse(value).ok_or_else(|| {
+                        Error::BadRequest(format!("Invalid {}: {}", name, value))
+                    })
+                })
+                .transpose()
+        };
+        match (parse("If-Match"), parse("If-None-Match")) {
+            (Ok(if_match), Ok(if_none_match)) => Outcome::Success(Precondition { if_match, if_none_match }),
+            (Err(e), _) | (_, Err(e)) => Outcome::Failure((Status::BadRequest, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn precondition(if_match: Option<&str>, if_none_match: Option<&str>) -> Precondition {
+        Precondition {
+            if_match: if_match.map(|tags| Tags::parse(tags).unwrap()),
+            if_none_match: if_none_match.map(|tags| Tags::parse(tags).unwrap()),
+        }
+    }
+
+    #[test]
+    fn no_precondition_always_holds() {
+        assert!(Precondition::default().check(None).is_ok());
+        assert!(Precondition::default().check(Some(3)).is_ok());
+    }
+
+    #[test]
+    fn if_match_requires_a_listed_version() {
+        let precondition = precondition(Some("\"3\", W/\"4\""), None);
+        assert!(precondition.check(Some(3)).is_ok());
+        assert!(precondition.check(Some(4)).is_ok());
+        assert!(matches!(precondition.check(Some(5)), Err(WriteError::PreconditionFailed)));
+        assert!(matches!(precondition.check(None), Err(WriteError::PreconditionFailed)));
+    }
+
+    #[test]
+    fn if_match_any_requires_an_entry() {
+        let precondition = precondition(Some("*"), None);
+        assert!(precondition.check(Some(1)).is_ok());
+        assert!(matches!(precondition.check(None), Err(WriteError::PreconditionFailed)));
+    }
+
+    #[test]
+    fn if_none_match_any_requires_no_entry() {
+        let precondition = precondition(None, Some("*"));
+        assert!(precondition.check(None).is_ok());
+        assert!(matches!(precondition.check(Some(1)), Err(WriteError::KeyExists)));
+    }
+
+    #[test]
+    fn if_none_match_rejects_listed_versions() {
+        let precondition = precondition(None, Some("\"2\""));
+        assert!(precondition.check(Some(1)).is_ok());
+        assert!(matches!(precondition.check(Some(2)), Err(WriteError::PreconditionFailed)));
+    }
+
+    #[test]
+    fn rejects_malformed_tags() {
+        for header in ["3", "\"x\"", "\"3\",", ""] {
+            assert_eq!(Tags::parse(header), None, "{}", header);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use serde_json::{ Deserializer, Value };
+use std::cmp::Ordering;
+
+/// A location within a JSON document: a JSON Pointer such as `/user/name`, or a JSONPath
+/// such as `$.user.name`, `$['user']['name']`, `$.items[0]`, `$.items[-1]` or `$.items[*].id`.
+#[derive(Debug, Clone)]
+pub enum Path {
+    Pointer(String),
+    JsonPath(Vec<Segment>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Segment {
+    /// A member of an object.
+    Name(String),
+    /// An element of an array, counted from the end if negative.
+    Index(i64),
+    /// Every member of an object or element of an array.
+    Wildcard,
+}
+
+impl Path {
+    /// Parses a JSON Pointer, a JSONPath, or a bare member name as shorthand for `$.name`.
+    pub fn parse(path: &str) -> Option<Self> {
+        if path.is_empty() || path.starts_with('/') {
+            return Some(Path::Pointer(path.to_string()));
+        }
+        match path.strip_prefix('$') {
+            Some(rest) => parse_segments(rest).map(Path::JsonPath),
+            None => parse_segments(&format!(".{}", path)).map(Path::JsonPath),
+        }
+    }
+
+    /// Returns true if the path selects at most one value.
+    pub fn is_singular(&self) -> bool {
+        match self {
+            Path::Pointer(_) => true,
+            Path::JsonPath(segments) => {
+                !segments.iter().any(|segment| matches!(segment, Segment::Wildcard))
+            }
+        }
+    }
+
+    /// Returns the values the path selects in `doc`.
+    pub fn select<'a>(&self, doc: &'a Value) -> Vec<&'a Value> {
+        let segments = match self {
+            Path::Pointer(pointer) => {
+                return doc.pointer(pointer).into_iter().collect();
+            }
+            Path::JsonPath(segments) => segments,
+        };
+        let mut selected = vec![doc];
+        for segment in segments {
+            selected = selected
+                .into_iter()
+                .flat_map(|value| children(value, segment))
+                .collect();
+        }
+        selected
+    }
+}
+
+fn children<'a>(value: &'a Value, segment: &Segment) -> Vec<&'a Value> {
+    match (segment, value) {
+        (Segment::Name(name), Value::Object(object)) => object.get(name).into_iter().collect(),
+        (Segment::Index(index), Value::Array(array)) => {
+            let index = if *index < 0 { (array.len() as i64) + index } else { *index };
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| array.get(index))
+                .into_iter()
+                .collect()
+        }
+        (Segment::Wildcard, Value::Object(object)) => object.values().collect(),
+        (Segment::Wildcard, Value::Array(array)) => array.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parses the segments following the `$` of a JSONPath.
+fn parse_segments(mut rest: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            let segment = match &after[..end] {
+                "" => {
+                    return None;
+                }
+                "*" => Segment::Wildcard,
+                name => Segment::Name(name.to_string()),
+            };
+            segments.push(segment);
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']')?;
+            let selector = after[..end].trim();
+            let segment = match selector {
+                "*" => Segment::Wildcard,
+                _ if selector.starts_with(['\'', '"']) => {
+                    let quote = &selector[..1];
+                    let name = selector[1..].strip_suffix(quote)?;
+                    Segment::Name(name.to_string())
+                }
+                _ => Segment::Index(selector.parse().ok()?),
+            };
+            segments.push(segment);
+            rest = &after[end + 1..];
+        } else {
+            return None;
+        }
+    }
+    Some(segments)
+}
+
+/// A condition on JSON documents, such as `status == "active" && $.age >= 18`.
+///
+/// Each clause compares the values at a path with a JSON literal; a document matches if,
+/// for every clause, some value at the path satisfies the comparison.
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    clauses: Vec<(Path, Comparison, Value)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Predicate {
+    pub fn parse(predicate: &str) -> Option<Self> {
+        let mut clauses = Vec::new();
+        let mut rest = predicate.trim_start();
+        loop {
+            let end = rest.find(|c: char| c.is_whitespace() || "=!<>".contains(c))?;
+            let path = Path::parse(&rest[..end])?;
+            rest = rest[end..].trim_start();
+
+            let (comparison, len) = [
+                ("==", Comparison::Eq),
+                ("!=", Comparison::Ne),
+                ("<=", Comparison::Le),
+                (">=", Comparison::Ge),
+                ("<", Comparison::Lt),
+                (">", Comparison::Gt),
+            ]
+                .into_iter()
+                .find(|(op, _)| rest.starts_with(op))
+                .map(|(op, comparison)| (comparison, op.len()))?;
+            rest = &rest[len..];
+
+            // The literal is the longest prefix of the rest that parses as JSON.
+            let mut literals = Deserializer::from_str(rest).into_iter::<Value>();
+            let literal = literals.next()?.ok()?;
+            rest = rest[literals.byte_offset()..].trim_start();
+            clauses.push((path, comparison, literal));
+
+            if rest.is_empty() {
+                return Some(Predicate { clauses });
+            }
+            rest = rest.strip_prefix("&&")?.trim_start();
+        }
+    }
+
+    /// Returns true if `doc` satisfies every clause.
+    pub fn matches(&self, doc: &Value) -> bool {
+        self.clauses.iter().all(|(path, comparison, literal)| {
+            path.select(doc)
+                .into_iter()
+                .any(|value| comparison.holds(value, literal))
+        })
+    }
+}
+
+impl Comparison {
+    fn holds(self, value: &Value, literal: &Value) -> bool {
+        let ordering = match (value, literal) {
+            (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ if value == literal => Some(Ordering::Equal),
+            _ => None,
+        };
+        match self {
+            Comparison::Eq => ordering == Some(Ordering::Equal),
+            Comparison::Ne => ordering != Some(Ordering::Equal),
+            Comparison::Lt => ordering == Some(Ordering::Less),
+            Comparison::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Comparison::Gt => ordering == Some(Ordering::Greater),
+            Comparison::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(predicate: &str, doc: Value) -> bool {
+        Predicate::parse(predicate).unwrap().matches(&doc)
+    }
+
+    #[test]
+    fn parses_comparisons() {
+        let doc = json!({ "status": "active", "age": 30, "tags": ["a", "b"] });
+        assert!(matches("status == \"active\"", doc.clone()));
+        assert!(matches("$.age >= 18", doc.clone()));
+        assert!(matches("age<31", doc.clone()));
+        assert!(matches("age != 29", doc.clone()));
+        assert!(!matches("age > 30", doc.clone()));
+        assert!(matches("$.tags[*] == \"b\"", doc.clone()));
+        assert!(matches("/status == \"active\"", doc));
+    }
+
+    #[test]
+    fn parses_conjunctions() {
+        let doc = json!({ "status": "active", "age": 17 });
+        assert!(matches("status == \"active\" && age < 18", doc.clone()));
+        assert!(!matches("status == \"active\" && age >= 18", doc));
+    }
+
+    #[test]
+    fn missing_paths_do_not_match() {
+        assert!(!matches("missing == null", json!({})));
+        assert!(!matches("age < 18", json!({ "age": "young" })));
+    }
+
+    #[test]
+    fn rejects_malformed_predicates() {
+        for predicate in ["", "status", "status ==", "status == active", "status = 1", "a == 1 &&", "$. == 1"] {
+            assert!(Predicate::parse(predicate).is_none(), "{}", predicate);
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use crate::engine::{ Data, Entry, Restored };
+use crate::eviction::Access;
+use crate::index::Indexes;
+use crate::{ from_unix_millis, to_unix_millis };
+use async_std::task;
+use micro_kv::storage::ShardedMap;
+use slog::Logger;
+use std::collections::{ BTreeSet, HashMap };
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+const MAGIC: &[u8; 4] = b"MKVS";
+/// Version 1 files hold only JSON values; version 2 adds raw byte values, version 3 entry
+/// versions, version 4 sliding TTLs, version 5 the highest version issued and version 6 the
+/// indexes created at runtime.
+const VERSION: u8 = 6;
+const KIND_JSON: u8 = 0;
+const KIND_BYTES: u8 = 1;
+const PREFIX: &str = "snapshot-";
+const EXTENSION: &str = ".mkvs";
+
+/// A key, its value, its deadline in milliseconds since the Unix epoch, its version and
+/// its sliding TTL in milliseconds.
+type SnapshotEntry = (String, Data, Option<u64>, u64, Option<u64>);
+
+/// Copies the contents of `entries`, locking one shard at a time.
+pub fn capture(entries: &ShardedMap<Entry>) -> Vec<SnapshotEntry> {
+    let mut captured = Vec::new();
+    for shard in entries.shards() {
+        captured.extend(
+            shard
+                .read()
+                .iter()
+                .map(|(key, entry)| {
+                    (
+                        key.clone(),
+                        entry.value.clone(),
+                        entry.expiry.map(to_unix_millis),
+                        entry.version,
+                        entry.sliding.map(|sliding| sliding.as_millis() as u64),
+                    )
+                })
+        );
+    }
+    captured
+}
+
+/// Writes `entries`, `version`, the highest entry version issued so far, and the paths of
+/// the `indexes` created at runtime to a new snapshot file in `dir` and prunes all but the
+/// newest `retain` files.
+///
+/// The file is written under a temporary name and renamed into place once synced,
+/// so a crash mid-write never leaves a partial snapshot behind.
+pub fn save(
+    dir: &Path,
+    entries: &[SnapshotEntry],
+    version: u64,
+    indexes: &BTreeSet<String>,
+    retain: usize
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&version.to_le_bytes());
+    body.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for (key, value, expires_at, entry_version, sliding_ms) in entries {
+        write_bytes(&mut body, key.as_bytes());
+        match value {
+            Data::Json(value) => {
+                body.push(KIND_JSON);
+                write_bytes(&mut body, value.to_string().as_bytes());
+            }
+            Data::Bytes { content_type, bytes } => {
+                body.push(KIND_BYTES);
+                write_bytes(&mut body, content_type.as_bytes());
+                write_bytes(&mut body, bytes);
+            }
+        }
+        // A deadline of zero marks a key without a TTL.
+        body.extend_from_slice(&expires_at.map_or(0, |millis| millis.max(1)).to_le_bytes());
+        body.extend_from_slice(&entry_version.to_le_bytes());
+        // Likewise a sliding TTL of zero marks a key whose expiry does not slide.
+        body.extend_from_slice(&sliding_ms.unwrap_or(0).to_le_bytes());
+    }
+    body.extend_from_slice(&(indexes.len() as u64).to_le_bytes());
+    for path in indexes {
+        write_bytes(&mut body, path.as_bytes());
+    }
+
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let name = format!("{}{:020}{}", PREFIX, millis, EXTENSION);
+    let path = dir.join(&name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&[VERSION])?;
+    file.write_all(&body)?;
+    file.write_all(&checksum(&body).to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(dir)?.sync_all()?;
+
+    for stale in list(dir)?.into_iter().skip(retain.max(1)) {
+        fs::remove_file(stale)?;
+    }
+    Ok(path)
+}
+
+/// Loads the newest valid snapshot in `dir`, skipping any that are corrupt with an error
+/// logged to `log`.
+///
+/// Keys whose deadline has already passed are dropped.
+pub fn load_latest(dir: &Path, log: &Logger) -> io::Result<Option<Restored>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    for path in list(dir)? {
+        match load(&path) {
+            Ok(loaded) => {
+                return Ok(Some(loaded));
+            }
+            Err(e) => slog::error!(log, "Skipping invalid snapshot {}: {:?}", path.display(), e),
+        }
+    }
+    Ok(None)
+}
+
+/// Reads and validates a single snapshot file, returning its entries, the highest version
+/// issued when it was saved, or zero for files written before that was recorded, and the
+/// indexes created at runtime.
+pub fn load(path: &Path) -> io::Result<Restored> {
+    let bytes = fs::read(path)?;
+    let header = MAGIC.len() + 1;
+    if bytes.len() < header + 16 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    let version = bytes[MAGIC.len()];
+    if !(1..=VERSION).contains(&version) {
+        return Err(invalid("unsupported snapshot version"));
+    }
+    let (body, trailer) = bytes[header..].split_at(bytes.len() - header - 8);
+    if checksum(body).to_le_bytes() != trailer {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let mut cursor = body;
+    let issued = if version < 5 { 0 } else { read_u64(&mut cursor)? };
+    let count = read_u64(&mut cursor)?;
+    let mut entries = HashMap::new();
+    for _ in 0..count {
+        let key = read_string(&mut cursor)?;
+        let kind = if version == 1 { KIND_JSON } else { take(&mut cursor, 1)?[0] };
+        let value = match kind {
+            KIND_JSON => Data::Json(Arc::new(serde_json::from_str(&read_string(&mut cursor)?)?)),
+            KIND_BYTES => {
+                let content_type = read_string(&mut cursor)?;
+                let bytes = read_bytes(&mut cursor)?.into();
+                Data::Bytes { content_type, bytes }
+            }
+            _ => {
+                return Err(invalid("unknown value kind"));
+            }
+        };
+        let deadline = read_u64(&mut cursor)?;
+        // Entries from files without versions are given fresh ones when loaded.
+        let entry_version = if version < 3 { 0 } else { read_u64(&mut cursor)? };
+        let sliding = match if version < 4 { 0 } else { read_u64(&mut cursor)? } {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        };
+        let expiry = match deadline {
+            0 => None,
+            millis =>
+                match from_unix_millis(millis) {
+                    Some(expiry) => Some(expiry),
+                    None => {
+                        continue;
+                    }
+                }
+        };
+        let access = Access::new();
+        let logged_expiry = expiry;
+        let entry = Entry { value, expiry, logged_expiry, sliding, version: entry_version, access };
+        entries.insert(key, entry);
+    }
+    let mut indexes = BTreeSet::new();
+    for _ in 0..if version < 6 { 0 } else { read_u64(&mut cursor)? } {
+        indexes.insert(read_string(&mut cursor)?);
+    }
+    Ok((entries, issued, indexes))
+}
+
+/// Periodically saves a snapshot of `entries`, of the highest version issued read from
+/// `version` and of the `indexes` created at runtime, to `dir`, reporting failures to `log`.
+pub async fn save_periodically(
+    entries: Arc<ShardedMap<Entry>>,
+    version: Arc<AtomicU64>,
+    indexes: Arc<Indexes>,
+    dir: PathBuf,
+    interval: Duration,
+    retain: usize,
+    log: Logger
+) {
+    loop {
+        task::sleep(interval).await;
+
+        let captured = capture(&entries);
+        // Read after the capture, so it covers the versions of every captured entry.
+        let issued = version.load(Ordering::Relaxed);
+        let paths = indexes.persisted();
+        let dir = dir.clone();
+        let save = move || save(&dir, &captured, issued, &paths, retain);
+        let result = task::spawn_blocking(save).await;
+        if let Err(e) = result {
+            slog::error!(log, "Error saving snapshot: {:?}", e);
+        }
+    }
+}
+
+/// Lists snapshot files in `dir`, newest first.
+fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_snapshot = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(PREFIX) && name.ends_with(EXTENSION));
+        if is_snapshot {
+            paths.push(path);
+        }
+    }
+    paths.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(paths)
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn read_u64(cursor: &mut &[u8]) -> io::Result<u64> {
+    let bytes = take(cursor, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_bytes<'a>(cursor: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = u32::from_le_bytes(take(cursor, 4)?.try_into().unwrap());
+    take(cursor, len as usize)
+}
+
+fn read_string(cursor: &mut &[u8]) -> io::Result<String> {
+    let bytes = read_bytes(cursor)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("invalid UTF-8"))
+}
+
+fn take<'a>(cursor: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if cursor.len() < len {
+        return Err(invalid("unexpected end of snapshot"));
+    }
+    let (head, tail) = cursor.split_at(len);
+    *cursor = tail;
+    Ok(head)
+}
+
+/// 64-bit FNV-1a hash, used to detect torn or corrupted snapshot files.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ (byte as u64)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{ json, Value };
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    fn discard() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// Returns a new, empty temporary directory.
+    fn snapshot_dir() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("micro_kv-snapshot-{}-{}", std::process::id(), count);
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn json_entry(key: &str, value: Value, expires_at: Option<u64>) -> SnapshotEntry {
+        (key.to_string(), Data::Json(Arc::new(value)), expires_at, 1, None)
+    }
+
+    /// Saves a snapshot, waiting first so its name sorts after that of the previous one.
+    fn save_later(dir: &Path, entries: &[SnapshotEntry], retain: usize) -> PathBuf {
+        thread::sleep(Duration::from_millis(2));
+        save(dir, entries, 1, &BTreeSet::new(), retain).unwrap()
+    }
+
+    fn unix_millis(from_now: Duration) -> u64 {
+        (SystemTime::now() + from_now).duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+
+    /// Encodes a snapshot of a single JSON entry in the layout of format `version`.
+    fn legacy(version: u8, key: &str, value: &str, deadline: u64) -> Vec<u8> {
+        let mut body = Vec::new();
+        if version >= 5 {
+            body.extend_from_slice(&9u64.to_le_bytes());
+        }
+        body.extend_from_slice(&1u64.to_le_bytes());
+        write_bytes(&mut body, key.as_bytes());
+        if version >= 2 {
+            body.push(KIND_JSON);
+        }
+        write_bytes(&mut body, value.as_bytes());
+        body.extend_from_slice(&deadline.to_le_bytes());
+        if version >= 3 {
+            body.extend_from_slice(&7u64.to_le_bytes());
+        }
+        if version >= 4 {
+            body.extend_from_slice(&30_000u64.to_le_bytes());
+        }
+        if version >= 6 {
+            body.extend_from_slice(&1u64.to_le_bytes());
+            write_bytes(&mut body, b"$.a");
+        }
+        let mut file = MAGIC.to_vec();
+        file.push(version);
+        file.extend_from_slice(&body);
+        file.extend_from_slice(&checksum(&body).to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn round_trips_json_and_bytes() {
+        let dir = snapshot_dir();
+        let deadline = unix_millis(Duration::from_secs(3600));
+        let content_type = "image/png".to_string();
+        let bytes = Data::Bytes { content_type, bytes: vec![0xff, 0].into() };
+        let entries = vec![
+            (
+                "doc".to_string(),
+                Data::Json(Arc::new(json!({ "a": [1, "two"] }))),
+                Some(deadline),
+                4,
+                Some(60_000),
+            ),
+            ("raw".to_string(), bytes, None, 5, None),
+        ];
+        let indexes = BTreeSet::from(["$.a".to_string(), "$.b[0]".to_string()]);
+        save(&dir, &entries, 11, &indexes, 1).unwrap();
+
+        let (loaded, issued, loaded_indexes) = load_latest(&dir, &discard()).unwrap().unwrap();
+        assert_eq!(issued, 11);
+        assert_eq!(loaded_indexes, indexes);
+        let doc = &loaded["doc"];
+        assert!(matches!(&doc.value, Data::Json(value) if **value == json!({ "a": [1, "two"] })));
+        assert!(doc.expiry.is_some());
+        assert_eq!(doc.version, 4);
+        assert_eq!(doc.sliding, Some(Duration::from_secs(60)));
+        let raw = &loaded["raw"];
+        match &raw.value {
+            Data::Bytes { content_type, bytes } => {
+                assert_eq!(content_type, "image/png");
+                assert_eq!(&bytes[..], &[0xff, 0]);
+            }
+            Data::Json(_) => panic!("bytes loaded as JSON"),
+        }
+        assert_eq!((raw.expiry, raw.version, raw.sliding), (None, 5, None));
+    }
+
+    #[test]
+    fn drops_keys_whose_deadline_passed() {
+        let dir = snapshot_dir();
+        let entries = vec![
+            json_entry("past", json!(1), Some(unix_millis(Duration::ZERO) - 1000)),
+            json_entry("future", json!(2), Some(unix_millis(Duration::from_secs(3600)))),
+            json_entry("forever", json!(3), None),
+        ];
+        save(&dir, &entries, 1, &BTreeSet::new(), 1).unwrap();
+        let (loaded, _, _) = load_latest(&dir, &discard()).unwrap().unwrap();
+        let mut keys: Vec<&String> = loaded.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["forever", "future"]);
+    }
+
+    #[test]
+    fn skips_corrupt_and_truncated_snapshots() {
+        let dir = snapshot_dir();
+        save_later(&dir, &[json_entry("old", json!(1), None)], 3);
+        let corrupt = save_later(&dir, &[json_entry("corrupt", json!(2), None)], 3);
+        let truncated = save_later(&dir, &[json_entry("truncated", json!(3), None)], 3);
+
+        let mut bytes = fs::read(&corrupt).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        fs::write(&corrupt, bytes).unwrap();
+        let len = fs::metadata(&truncated).unwrap().len();
+        File::options().write(true).open(&truncated).unwrap().set_len(len - 3).unwrap();
+
+        assert!(load(&corrupt).is_err());
+        assert!(load(&truncated).is_err());
+        let (loaded, _, _) = load_latest(&dir, &discard()).unwrap().unwrap();
+        assert!(loaded.contains_key("old"));
+        assert_eq!(loaded.len(), 1);
+    }
+
+    #[test]
+    fn keeps_only_the_newest_retained_snapshots() {
+        let dir = snapshot_dir();
+        let paths: Vec<PathBuf> = (0..4)
+            .map(|i| save_later(&dir, &[json_entry("key", json!(i), None)], 2))
+            .collect();
+        assert_eq!(list(&dir).unwrap(), [paths[3].clone(), paths[2].clone()]);
+        let (loaded, _, _) = load_latest(&dir, &discard()).unwrap().unwrap();
+        assert!(matches!(&loaded["key"].value, Data::Json(value) if **value == json!(3)));
+    }
+
+    #[test]
+    fn loads_older_formats() {
+        let deadline = unix_millis(Duration::from_secs(3600));
+        for version in 1..=VERSION {
+            let dir = snapshot_dir();
+            let path = dir.join(format!("{}{:020}{}", PREFIX, 1, EXTENSION));
+            fs::write(&path, legacy(version, "key", "{\"a\":1}", deadline)).unwrap();
+
+            let (loaded, issued, indexes) = load(&path).unwrap();
+            let entry = &loaded["key"];
+            assert!(matches!(&entry.value, Data::Json(value) if **value == json!({ "a": 1 })));
+            assert!(entry.expiry.is_some());
+            assert_eq!(entry.version, if version >= 3 { 7 } else { 0 }, "version {}", version);
+            let sliding = (version >= 4).then_some(Duration::from_secs(30));
+            assert_eq!(entry.sliding, sliding, "version {}", version);
+            assert_eq!(issued, if version >= 5 { 9 } else { 0 }, "version {}", version);
+            assert_eq!(indexes.len(), if version >= 6 { 1 } else { 0 }, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let dir = snapshot_dir();
+        let path = dir.join(format!("{}{:020}{}", PREFIX, 1, EXTENSION));
+        fs::write(&path, legacy(VERSION + 1, "key", "1", 0)).unwrap();
+        assert!(load(&path).is_err());
+        assert!(load_latest(&dir, &discard()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,162 @@
+use parking_lot::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::ops::{ Deref, DerefMut };
+use std::thread;
+
+/// A concurrent map split into independently locked shards.
+///
+/// Each key belongs to exactly one shard, chosen by its hash, so operations on
+/// keys in different shards never contend. Locks do not poison, so a panic while
+/// a shard is held does not take the rest of the map down with it.
+///
+/// Shards keep their keys sorted, so a range of keys can be read from each shard
+/// and the results merged, without visiting the rest of the map.
+pub struct ShardedMap<V> {
+    shards: Box<[RwLock<BTreeMap<String, V>>]>,
+    hasher: RandomState,
+}
+
+impl<V> ShardedMap<V> {
+    /// Creates a map with four shards per available CPU.
+    pub fn new() -> Self {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(cpus * 4)
+    }
+
+    /// Creates a map with `count` shards, rounded up to the next power of two.
+    pub fn with_shards(count: usize) -> Self {
+        let count = count.max(1).next_power_of_two();
+        ShardedMap {
+            shards: (0..count).map(|_| RwLock::new(BTreeMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Locks the shard holding `key` for reading.
+    pub fn read(&self, key: &str) -> RwLockReadGuard<'_, BTreeMap<String, V>> {
+        self.shard(key).read()
+    }
+
+    /// Locks the shard holding `key` for writing.
+    pub fn write(&self, key: &str) -> RwLockWriteGuard<'_, BTreeMap<String, V>> {
+        self.shard(key).write()
+    }
+
+    /// Locks the shards holding `keys` for reading, all at once.
+    pub fn read_many<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>
+    ) -> Locked<'_, V, RwLockReadGuard<'_, BTreeMap<String, V>>> {
+        self.lock_many(keys, |shard| shard.read())
+    }
+
+    /// Locks the shards holding `keys` for writing, all at once.
+    pub fn write_many<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>
+    ) -> Locked<'_, V, RwLockWriteGuard<'_, BTreeMap<String, V>>> {
+        self.lock_many(keys, |shard| shard.write())
+    }
+
+    /// Returns every shard, for operations that visit the whole map one shard at a time.
+    pub fn shards(&self) -> &[RwLock<BTreeMap<String, V>>] {
+        &self.shards
+    }
+
+    /// Returns the number of entries, which may be stale by the time it is used.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<BTreeMap<String, V>> {
+        &self.shards[self.index(key)]
+    }
+
+    fn index(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) as usize) & (self.shards.len() - 1)
+    }
+
+    /// Locks each distinct shard holding one of `keys`, always in ascending shard order
+    /// so that concurrent callers cannot deadlock.
+    fn lock_many<'a, 's, G>(
+        &'s self,
+        keys: impl IntoIterator<Item = &'a str>,
+        lock: impl Fn(&'s RwLock<BTreeMap<String, V>>) -> G
+    ) -> Locked<'s, V, G> {
+        let mut indices: Vec<usize> = keys
+            .into_iter()
+            .map(|key| self.index(key))
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+        Locked {
+            map: self,
+            guards: indices
+                .into_iter()
+                .map(|index| (index, lock(&self.shards[index])))
+                .collect(),
+        }
+    }
+}
+
+/// Guards over several shards of a `ShardedMap`, held together.
+pub struct Locked<'a, V, G> {
+    map: &'a ShardedMap<V>,
+    /// Guards sorted by shard index.
+    guards: Vec<(usize, G)>,
+}
+
+impl<V, G: Deref<Target = BTreeMap<String, V>>> Locked<'_, V, G> {
+    /// Returns the shard holding `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was not among the keys the shards were locked for.
+    pub fn shard(&self, key: &str) -> &BTreeMap<String, V> {
+        &self.guards[self.position(key)].1
+    }
+
+    fn position(&self, key: &str) -> usize {
+        let index = self.map.index(key);
+        self.guards
+            .binary_search_by_key(&index, |(i, _)| *i)
+            .expect("shard of key was not locked")
+    }
+}
+
+impl<V, G: DerefMut<Target = BTreeMap<String, V>>> Locked<'_, V, G> {
+    /// Returns the shard holding `key` for modification.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was not among the keys the shards were locked for.
+    pub fn shard_mut(&mut self, key: &str) -> &mut BTreeMap<String, V> {
+        let position = self.position(key);
+        &mut self.guards[position].1
+    }
+}
+
+impl<V> Default for ShardedMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> FromIterator<(String, V)> for ShardedMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let map = ShardedMap::new();
+        for (key, value) in iter {
+            map.write(&key).insert(key, value);
+        }
+        map
+    }
+}
@@ -0,0 +1,91 @@
+use crate::engine::Entry;
+use crate::error::Error;
+use crate::{ parse_query, ExpiryTime };
+use rocket::http::Status;
+use rocket::request::{ FromRequest, Outcome, Request };
+use std::str::FromStr;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+/// How a write sets the expiry of an entry, taken from the `ttl`, `ttl_ms`, `keepttl` and
+/// `sliding` query parameters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TtlOptions {
+    /// New time to live, given in whole seconds with `ttl` or milliseconds with `ttl_ms`.
+    pub ttl: Option<Duration>,
+    /// Keep the current expiry of the entry rather than removing it when no `ttl` is given.
+    pub keep: bool,
+    /// Renew the expiry to `ttl` from now whenever the entry is read.
+    pub sliding: bool,
+}
+
+impl TtlOptions {
+    /// Returns the expiry and sliding TTL of an entry written at `now` over `current`.
+    pub fn apply(&self, current: Option<&Entry>, now: Instant) -> (ExpiryTime, Option<Duration>) {
+        match self.ttl {
+            // TTLs whose deadline cannot be represented are rejected when parsed.
+            Some(ttl) => (deadline(now, ttl), self.sliding.then_some(ttl)),
+            None if self.keep => current.map_or((None, None), |entry| (entry.expiry, entry.sliding)),
+            None => (None, None),
+        }
+    }
+}
+
+/// Returns the deadline `ttl` after `now`, or `None` if it is too far in the future to be
+/// represented, including as milliseconds since the Unix epoch in the append-only log.
+pub fn deadline(now: Instant, ttl: Duration) -> Option<Instant> {
+    let wall_clock = SystemTime::now().checked_add(ttl)?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(wall_clock.as_millis()).ok()?;
+    now.checked_add(ttl)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TtlOptions {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match parse(req) {
+            Ok(options) => Outcome::Success(options),
+            Err(e) => Outcome::Failure((Status::BadRequest, e)),
+        }
+    }
+}
+
+fn parse(req: &Request<'_>) -> Result<TtlOptions, Error> {
+    let ttl = ttl_query(req, "ttl", Duration::from_secs)?;
+    let ttl_ms = ttl_query(req, "ttl_ms", Duration::from_millis)?;
+    let keep = query::<bool>(req, "keepttl")?.unwrap_or(false);
+    let sliding = query::<bool>(req, "sliding")?.unwrap_or(false);
+    if ttl.is_some() && ttl_ms.is_some() {
+        return Err(Error::BadRequest("Only one of ttl and ttl_ms may be given".to_string()));
+    }
+    let ttl = ttl.or(ttl_ms);
+    if keep && ttl.is_some() {
+        return Err(Error::BadRequest("keepttl may not be given with a ttl".to_string()));
+    }
+    if sliding && ttl.is_none() {
+        return Err(Error::BadRequest("sliding requires a ttl".to_string()));
+    }
+    Ok(TtlOptions { ttl, keep, sliding })
+}
+
+/// Parses an optional TTL query parameter in the unit `from`, rejecting TTLs too long to
+/// set a deadline with.
+fn ttl_query(
+    req: &Request<'_>,
+    name: &str,
+    from: fn(u64) -> Duration
+) -> Result<Option<Duration>, Error> {
+    let Some(value) = query::<u64>(req, name)? else {
+        return Ok(None);
+    };
+    let ttl = from(value);
+    if deadline(Instant::now(), ttl).is_none() {
+        return Err(Error::BadRequest(format!("Invalid {}: {}", name, value)));
+    }
+    Ok(Some(ttl))
+}
+
+/// Returns the value of the query parameter `name` parsed as `T`, if it is given.
+fn query<T: FromStr>(req: &Request<'_>, name: &str) -> Result<Option<T>, Error> {
+    parse_query(name, req.query_value::<&str>(name).and_then(Result::ok))
+}